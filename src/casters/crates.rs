@@ -3,9 +3,22 @@ use std::time::Duration;
 use log::{debug, info, warn};
 use tokio::task::{spawn_blocking, JoinHandle};
 
-use crate::{get_db, CratesConfig, Event, Interval, TX};
+use crate::{get_db, Caster, CratesConfig, Event, Interval, Outbox};
 
-pub fn run_crates(tx: TX, config: CratesConfig) -> JoinHandle<()> {
+/// Caster of new versions of crates published on crates.io
+pub struct CratesCaster;
+
+impl Caster for CratesCaster {
+    type Config = CratesConfig;
+
+    const NAME: &'static str = "caster_crates";
+
+    fn run(tx: Outbox, config: CratesConfig) -> JoinHandle<()> {
+        run_crates(tx, config)
+    }
+}
+
+pub fn run_crates(tx: Outbox, config: CratesConfig) -> JoinHandle<()> {
     spawn_blocking(move || {
         let db = get_db();
         let mut index = crates_index::Index::new_cargo_default().unwrap();
@@ -37,17 +50,20 @@ pub fn run_crates(tx: TX, config: CratesConfig) -> JoinHandle<()> {
                     }
                 }
 
-                if let Err(e) = db.insert(ver_id, cksm) {
-                    warn!("Failed to insert data to db: {e}")
-                }
-
-                tx.send(Event::CratesIo {
+                let event = Event::CratesIo {
                     name: crate_name.to_owned(),
                     vers: ver.version().to_owned(),
                     links: ver.links().map(Into::into),
                     yanked: ver.is_yanked(),
-                })
-                .expect("All consumers stopped");
+                };
+                // Marked as seen once queued, so it's checked again otherwise
+                if let Err(e) = tx.send(event) {
+                    warn!("Failed to queue event: {:?}", e);
+                    continue;
+                }
+                if let Err(e) = db.insert(ver_id, cksm) {
+                    warn!("Failed to insert data to db: {e}")
+                }
             }
         }
     })
@@ -1,123 +1,350 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use color_eyre::{
     eyre::{eyre, Context},
     Result,
 };
 use feed_rs::model::Feed;
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::future::join_all;
 use log::{debug, info, warn};
+use regex::Regex;
+use reqwest::{
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    RequestBuilder, StatusCode,
+};
+use sled::Db;
 use tokio::task::JoinHandle;
 
-use crate::{get_client, get_db, get_hash, ts_to_systemtime, Event, FeedConfig, TX};
+use crate::{
+    get_client, get_db, get_hash, ts_to_systemtime, Caster, Event, FeedAuth, FeedConfig,
+    FeedSource, Hints, Outbox, Schedule,
+};
 
 const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
 
-pub fn run_feed(tx: TX, config: FeedConfig) -> JoinHandle<()> {
+/// Caster of RSS, Atom and JSON feeds
+pub struct FeedCaster;
+
+impl Caster for FeedCaster {
+    type Config = FeedConfig;
+
+    const NAME: &'static str = "caster_feed";
+
+    fn run(tx: Outbox, config: FeedConfig) -> JoinHandle<()> {
+        run_feed(tx, config)
+    }
+}
+
+pub fn run_feed(tx: Outbox, config: FeedConfig) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut timer = tokio::time::interval(Duration::from_secs_f64(config.interval));
         let db = get_db();
         let count = db.scan_prefix("FEED-").count();
 
         info!("Found {} feeds cached in DB", count);
 
-        loop {
-            timer.tick().await;
-            let mut fut = config
-                .urls
-                .iter()
-                .map(|url| fetch_one(url))
-                .collect::<FuturesUnordered<_>>();
+        join_all(
+            config
+                .sources()
+                .into_iter()
+                .map(|source| run_source(tx.clone(), source)),
+        )
+        .await;
+    })
+}
+
+/// Poll one feed on its own schedule
+async fn run_source(tx: Outbox, source: FeedSource) {
+    let db = get_db();
+    let feed_id = get_hash(&source.url);
+    let secs = |x: Option<f64>| Duration::from_secs_f64(x.unwrap_or_default());
+    let mut schedule = Schedule::new(
+        secs(source.interval),
+        secs(source.min_interval),
+        secs(source.max_interval),
+    );
 
-            while let Some(e) = fut.next().await {
-                match e {
-                    Err(e) => {
-                        warn!("{}", e)
-                    }
-                    Ok((feed_id, feed)) => {
-                        for entry in feed.entries.into_iter() {
-                            let entry_id = format!("FEED-{}-{}", &feed_id, get_hash(entry.id));
-
-                            let timestamp = entry
-                                .published
-                                .or(entry.updated)
-                                .map(|time| time.timestamp())
-                                .unwrap_or_default();
-
-                            debug!("Entry fetched: {}, published at {}", entry_id, timestamp);
-
-                            let data = timestamp.to_be_bytes();
-
-                            // Entry already exists
-                            if let Ok(Some(ref v)) = db.get(&entry_id) {
-                                // Entry not updated, early return
-                                if v == &data {
-                                    return;
-                                }
-                                // Update entry
-                                if let Err(e) = db.insert(&entry_id, &data) {
-                                    warn!("Failed to insert data to db: {}", e)
-                                };
-                            } else {
-                                // Entry does not exist, insert entry
-                                if let Err(e) = db.insert(&entry_id, &data) {
-                                    warn!("Failed to insert data to db: {}", e)
-                                };
-
-                                if let Ok(true) = ts_to_systemtime(timestamp as u64)
-                                    .elapsed()
-                                    .map(|x| x.as_secs() > SECONDS_PER_DAY * config.ignore_days)
-                                {
-                                    // Newly seen entry that is old, ignoring
-                                    info!("Found old entry, ignored");
-                                    return;
-                                }
-                            }
-
-                            let title = entry.title.map(|title| title.content);
-                            let link = entry.links.into_iter().next().map(|x| x.href);
-                            let content = entry
-                                .summary
-                                .map(|content| content.content)
-                                .or_else(|| entry.content.and_then(|x| x.body));
-
-                            // Emit event
-                            tx.send(Event::Feed {
-                                time: timestamp,
-                                entry_id,
-                                content,
-                                title,
-                                link,
-                            })
-                            .expect("All consumers stopped");
-                        }
-                    }
+    loop {
+        let validators = Validators::load(db, &feed_id);
+
+        let (hints, res) = fetch_one(&source, &validators).await;
+
+        let updated = match res {
+            Ok(Some((feed, validators))) => {
+                let res = emit_entries(&tx, db, &source, &feed_id, feed);
+
+                // Fetched again in full if some entry failed to be queued
+                if res.is_ok() {
+                    validators.save(db, &feed_id);
                 }
 
                 if let Err(e) = db.flush_async().await {
                     warn!("Error flushing content to db: {}", e)
                 }
+
+                match res {
+                    Ok(count) => count > 0,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        false
+                    }
+                }
+            }
+            Ok(None) => {
+                debug!("Feed not modified: {}", source.url);
+                false
+            }
+            Err(e) => {
+                warn!("{:?}", e);
+                false
+            }
+        };
+
+        let delay = schedule.next(updated, &hints, SystemTime::now());
+        debug!(
+            "Next fetch of {} in {}",
+            source.url,
+            humantime::format_duration(Duration::from_secs(delay.as_secs()))
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Send new entries of `feed` as events, returning number of events sent.
+/// Entries are marked as seen only once queued, so on error the rest are sent
+/// on next fetch.
+fn emit_entries(
+    tx: &Outbox,
+    db: &Db,
+    source: &FeedSource,
+    feed_id: &str,
+    feed: Feed,
+) -> Result<usize> {
+    let mut count = 0;
+
+    for entry in feed.entries.into_iter() {
+        let entry_id = format!("FEED-{}-{}", &feed_id, get_hash(entry.id));
+
+        let timestamp = entry
+            .published
+            .or(entry.updated)
+            .map(|time| time.timestamp())
+            .unwrap_or_default();
+
+        debug!("Entry fetched: {}, published at {}", entry_id, timestamp);
+
+        let data = timestamp.to_be_bytes();
+
+        let mark_seen = || {
+            if let Err(e) = db.insert(&entry_id, &data) {
+                warn!("Failed to insert data to db: {}", e)
+            }
+        };
+
+        // Entry already exists
+        if let Ok(Some(ref v)) = db.get(&entry_id) {
+            // Entry not updated, skip
+            if v == &data {
+                continue;
+            }
+        } else {
+            // Entry does not exist
+            let ignore_days = source.ignore_days.unwrap_or_default();
+            if let Ok(true) = ts_to_systemtime(timestamp as u64)
+                .elapsed()
+                .map(|x| x.as_secs() > SECONDS_PER_DAY * ignore_days)
+            {
+                // Newly seen entry that is old, ignoring
+                info!("Found old entry, ignored");
+                mark_seen();
+                continue;
             }
         }
-    })
+
+        let title = entry.title.map(|title| title.content);
+        let link = entry.links.into_iter().next().map(|x| x.href);
+        let content = entry
+            .summary
+            .map(|content| content.content)
+            .or_else(|| entry.content.and_then(|x| x.body));
+
+        if !source.filter(title.as_deref(), content.as_deref()) {
+            debug!("Entry filtered: {}", entry_id);
+            mark_seen();
+            continue;
+        }
+
+        let content = match source.content_max_length {
+            Some(max_len) if max_len > 0 => content.map(|x| truncate(x, max_len)),
+            _ => content,
+        };
+
+        // Emit event
+        let event = Event::Feed {
+            feed: source.url.clone(),
+            name: source.name.clone(),
+            time: timestamp,
+            entry_id: entry_id.clone(),
+            content,
+            title,
+            link,
+        };
+        tx.send(event).wrap_err("Failed to queue event")?;
+        mark_seen();
+
+        count += 1;
+    }
+
+    Ok(count)
 }
 
-async fn fetch_one(url: &str) -> Result<(String, Feed)> {
-    let res = get_client()
-        .get(url)
+/// `ETag` and `Last-Modified` of last response of a resource (e.g. a feed),
+/// sent back with `If-None-Match` and `If-Modified-Since` so unchanged
+/// resources are answered with 304
+#[derive(Debug, Default)]
+pub struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn load(db: &Db, feed_id: &str) -> Self {
+        let get = |key: String| {
+            db.get(key)
+                .ok()
+                .flatten()
+                .and_then(|x| String::from_utf8(x.to_vec()).ok())
+        };
+        Self {
+            etag: get(format!("ETAG-{}", feed_id)),
+            last_modified: get(format!("LAST-MODIFIED-{}", feed_id)),
+        }
+    }
+
+    pub fn save(&self, db: &Db, feed_id: &str) {
+        for (key, value) in [
+            (format!("ETAG-{}", feed_id), &self.etag),
+            (format!("LAST-MODIFIED-{}", feed_id), &self.last_modified),
+        ] {
+            let res = match value {
+                Some(value) => db.insert(key, value.as_bytes()).map(drop),
+                None => db.remove(key).map(drop),
+            };
+            if let Err(e) = res {
+                warn!("Failed to insert data to db: {}", e)
+            }
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        Self {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+
+    /// Add conditional headers to `req`
+    pub fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(ref etag) = self.etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(ref last_modified) = self.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        req
+    }
+}
+
+impl FeedSource {
+    /// Check title and content of an entry against `include` and `exclude`
+    fn filter(&self, title: Option<&str>, content: Option<&str>) -> bool {
+        let matches = |regex: &Regex| {
+            title.is_some_and(|x| regex.is_match(x)) || content.is_some_and(|x| regex.is_match(x))
+        };
+        self.include.as_ref().is_none_or(matches) && !self.exclude.as_ref().is_some_and(matches)
+    }
+}
+
+fn truncate(content: String, max_len: usize) -> String {
+    if content.chars().count() > max_len {
+        content.chars().take(max_len).chain("...".chars()).collect()
+    } else {
+        content
+    }
+}
+
+/// Fetch a feed, or `None` if it's not modified since last fetch, along with
+/// hints from publisher on when to fetch again
+async fn fetch_one(
+    source: &FeedSource,
+    validators: &Validators,
+) -> (Hints, Result<Option<(Feed, Validators)>>) {
+    let url = source.url.as_str();
+    let res = match build_request(source, validators)
         .send()
         .await
-        .wrap_err_with(|| format!("Request failed: {}", url))?;
+        .wrap_err_with(|| format!("Request failed: {}", url))
+    {
+        Ok(res) => res,
+        Err(e) => return (Hints::default(), Err(e)),
+    };
+
+    let mut hints = Hints::from_headers(res.headers());
     let status = res.status();
-    if !status.is_success() {
-        let text = res.text().await.wrap_err("Decode failed")?;
-        Err(eyre!("{}", text).wrap_err(format!(
-            "Unsuccessful response from server (Code: {})",
-            status
-        )))
+    if !matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        hints.retry_after = None;
+    }
+
+    if status == StatusCode::NOT_MODIFIED {
+        (hints, Ok(None))
+    } else if !status.is_success() {
+        let res = match res.text().await.wrap_err("Decode failed") {
+            Ok(text) => Err(eyre!("{}", text).wrap_err(format!(
+                "Unsuccessful response from server (Code: {})",
+                status
+            ))),
+            Err(e) => Err(e),
+        };
+        (hints, res)
     } else {
-        let bytes = res.bytes().await?;
-        let feed = feed_rs::parser::parse(bytes.as_ref()).wrap_err("Failed to parse feed")?;
-        Ok((get_hash(url), feed))
+        let validators = Validators::from_headers(res.headers());
+        let bytes = match res.bytes().await {
+            Ok(bytes) => bytes,
+            Err(e) => return (hints, Err(e.into())),
+        };
+        match feed_rs::parser::parse(bytes.as_ref())
+            .wrap_err_with(|| format!("Failed to parse feed: {}", url))
+        {
+            Ok(feed) => (
+                hints.with_body(feed.ttl, bytes.as_ref()),
+                Ok(Some((feed, validators))),
+            ),
+            Err(e) => (hints, Err(e)),
+        }
+    }
+}
+
+fn build_request(source: &FeedSource, validators: &Validators) -> RequestBuilder {
+    let mut req = validators.apply(get_client().get(&source.url));
+
+    for (key, value) in source.headers.iter() {
+        req = req.header(key, value);
+    }
+
+    match source.auth {
+        Some(FeedAuth::Basic {
+            ref username,
+            ref password,
+        }) => req.basic_auth(username, password.as_ref()),
+        Some(FeedAuth::Bearer { ref token }) => req.bearer_auth(token),
+        None => req,
     }
 }
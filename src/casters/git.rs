@@ -0,0 +1,213 @@
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+use color_eyre::{eyre::Context, Result};
+use git2::{FetchOptions, FetchPrune, Oid, Repository, Sort};
+use log::{debug, info, warn};
+use sled::Db;
+use tokio::{
+    task::{spawn_blocking, JoinHandle},
+    time::MissedTickBehavior,
+};
+
+use crate::{get_db, get_hash, Caster, Event, GitConfig, GitRepo, Outbox};
+
+/// Fetch all branches as remote branches, and tags as they are
+const REFSPECS: &[&str] = &[
+    "+refs/heads/*:refs/remotes/origin/*",
+    "+refs/tags/*:refs/tags/*",
+];
+
+/// Caster of new commits and tags of git repositories
+pub struct GitCaster;
+
+impl Caster for GitCaster {
+    type Config = GitConfig;
+
+    const NAME: &'static str = "caster_git";
+
+    fn run(tx: Outbox, config: GitConfig) -> JoinHandle<()> {
+        run_git(tx, config)
+    }
+}
+
+pub fn run_git(tx: Outbox, config: GitConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let db = get_db();
+        let config = Arc::new(config);
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(config.interval));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            for i in 0..config.repos.len() {
+                let (tx, config) = (tx.clone(), config.clone());
+                // Fetching blocks, but running it in its own task keeps this
+                // one abortable
+                let res =
+                    spawn_blocking(move || check_repo(&tx, db, &config.repos[i], &config)).await;
+                match res {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("{:?}", e),
+                    Err(e) => warn!("Failed to check git repository: {}", e),
+                }
+            }
+
+            if let Err(e) = db.flush_async().await {
+                warn!("Error flushing content to db: {}", e)
+            }
+        }
+    })
+}
+
+/// Fetch `source` into cache and send new commits of watched branches, and new
+/// tags if configured. Branches and tags seen for the first time are only
+/// recorded.
+fn check_repo(tx: &Outbox, db: &Db, source: &GitRepo, config: &GitConfig) -> Result<()> {
+    debug!("Fetching git repository {}", source.url);
+    let repo_id = get_hash(&source.url);
+    let name = source.name.as_ref().unwrap_or(&source.url);
+    let repo = open(&Path::new(&config.cache_dir).join(&repo_id))?;
+
+    let mut options = FetchOptions::new();
+    options.prune(FetchPrune::On);
+    repo.remote_anonymous(&source.url)?
+        .fetch(REFSPECS, Some(&mut options), None)
+        .wrap_err_with(|| format!("Failed to fetch {}", source.url))?;
+
+    for reference in repo.references_glob("refs/remotes/origin/*")? {
+        let reference = reference?;
+        let branch = match reference
+            .name()
+            .and_then(|x| x.strip_prefix("refs/remotes/origin/"))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+        if !source.branches.is_empty() && !source.branches.iter().any(|x| x == branch) {
+            continue;
+        }
+
+        let head = reference.peel_to_commit()?.id();
+        let key = format!("GIT-{}-BRANCH-{}", repo_id, get_hash(branch));
+        let last = match db.get(&key)? {
+            Some(x) => Oid::from_bytes(&x)?,
+            None => {
+                debug!("New branch of {}: {}", name, branch);
+                db.insert(key, head.as_bytes())?;
+                continue;
+            }
+        };
+        if last == head {
+            continue;
+        }
+
+        // Newest first. A force push may have dropped the last head, then
+        // ancestors of the new head are sent up to `max_commits`.
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+        walk.push(head)?;
+        if walk.hide(last).is_err() {
+            debug!("Last head of {} {} is gone", name, branch)
+        }
+        let commits = walk
+            .take(config.max_commits)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for id in commits.into_iter().rev() {
+            let commit = repo.find_commit(id)?;
+            send(
+                tx,
+                Event::Git {
+                    repo: name.to_owned(),
+                    branch: Some(branch.to_owned()),
+                    tag: None,
+                    commit: id.to_string(),
+                    author: commit.author().name().unwrap_or_default().to_owned(),
+                    message: commit.message().unwrap_or_default().trim_end().to_owned(),
+                    time: commit.time().seconds(),
+                },
+            )?;
+        }
+        // Recorded once all are queued, so they're sent on next fetch otherwise
+        db.insert(key, head.as_bytes())?;
+    }
+
+    if !source.tags {
+        return Ok(());
+    }
+    // Marks that tags of the repository have been recorded once, so that the
+    // first tag of a repository without any is still sent. Set once all are
+    // recorded, so that old tags aren't sent if this is cut short.
+    let recorded = format!("GIT-{}-TAGS", repo_id);
+    let first = !db.contains_key(&recorded)?;
+    for tag_name in repo.tag_names(None)?.iter().flatten() {
+        let key = format!("GIT-{}-TAG-{}", repo_id, get_hash(tag_name));
+        if db.contains_key(&key)? {
+            continue;
+        }
+        if first {
+            db.insert(key, &[])?;
+            continue;
+        }
+
+        let reference = repo.find_reference(&format!("refs/tags/{}", tag_name))?;
+        let commit = match reference.peel_to_commit() {
+            Ok(x) => x,
+            Err(e) => {
+                debug!(
+                    "Tag {} of {} is not of a commit, ignored: {}",
+                    tag_name, name, e
+                );
+                db.insert(key, &[])?;
+                continue;
+            }
+        };
+        // Message and tagger of annotated tags, author of commit otherwise
+        let tag = reference.peel_to_tag().ok();
+        let message = tag.as_ref().and_then(|x| x.message()).unwrap_or_default();
+        let (author, time) = match tag.as_ref().and_then(|x| x.tagger()) {
+            Some(tagger) => (
+                tagger.name().unwrap_or_default().to_owned(),
+                tagger.when().seconds(),
+            ),
+            None => (
+                commit.author().name().unwrap_or_default().to_owned(),
+                commit.time().seconds(),
+            ),
+        };
+        send(
+            tx,
+            Event::Git {
+                repo: name.to_owned(),
+                branch: None,
+                tag: Some(tag_name.to_owned()),
+                commit: commit.id().to_string(),
+                author,
+                message: message.trim_end().to_owned(),
+                time,
+            },
+        )?;
+        db.insert(key, &[])?;
+    }
+    db.insert(recorded, &[])?;
+
+    Ok(())
+}
+
+/// Open bare repository cached at `path`, creating it if not exists
+fn open(path: &Path) -> Result<Repository> {
+    if let Ok(repo) = Repository::open_bare(path) {
+        return Ok(repo);
+    }
+    info!("Creating git repository at {}", path.display());
+    fs::create_dir_all(path)
+        .wrap_err_with(|| format!("Failed to create directory {}", path.display()))?;
+    Repository::init_bare(path)
+        .wrap_err_with(|| format!("Failed to create git repository at {}", path.display()))
+}
+
+fn send(tx: &Outbox, event: Event) -> Result<()> {
+    info!("New git event: {}", event);
+    tx.send(event).wrap_err("Failed to queue event")?;
+    Ok(())
+}
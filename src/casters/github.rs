@@ -0,0 +1,236 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use reqwest::{
+    header::{ACCEPT, USER_AGENT},
+    StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use sled::Db;
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{get_client, get_db, get_hash, Caster, Event, GitHubConfig, Outbox, Validators};
+
+/// Caster of new releases and tags of GitHub repositories
+pub struct GitHubCaster;
+
+impl Caster for GitHubCaster {
+    type Config = GitHubConfig;
+
+    const NAME: &'static str = "caster_github";
+
+    fn run(tx: Outbox, config: GitHubConfig) -> JoinHandle<()> {
+        run_github(tx, config)
+    }
+}
+
+/// A release as returned by the REST API
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    name: Option<String>,
+    body: Option<String>,
+    html_url: String,
+    draft: bool,
+    prerelease: bool,
+    published_at: Option<String>,
+}
+
+/// A tag as returned by the REST API
+#[derive(Debug, Deserialize)]
+struct Tag {
+    name: String,
+}
+
+pub fn run_github(tx: Outbox, config: GitHubConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let db = get_db();
+        let mut interval = tokio::time::interval(Duration::from_secs_f64(config.interval));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+            for repo in config.repos.iter() {
+                debug!("Checking GitHub repository {}", repo);
+                if let Err(e) = check_repo(&tx, db, repo, &config).await {
+                    warn!("{:?}", e)
+                }
+            }
+
+            if let Err(e) = db.flush_async().await {
+                warn!("Error flushing content to db: {}", e)
+            }
+        }
+    })
+}
+
+/// Send new releases of `repo`, and new tags if configured. Of a repository
+/// checked for the first time, only the latest release is sent.
+async fn check_repo(tx: &Outbox, db: &Db, repo: &str, config: &GitHubConfig) -> Result<()> {
+    let repo_id = get_hash(repo);
+    let first = db
+        .scan_prefix(format!("GITHUB-{}-", repo_id))
+        .next()
+        .is_none();
+    // Tags of releases already sent are left out, but not the other way
+    // round, as releases are usually created after their tags
+    let release_key = |tag: &str| format!("GITHUB-{}-RELEASE-{}", repo_id, get_hash(tag));
+    let tag_key = |tag: &str| format!("GITHUB-{}-TAG-{}", repo_id, get_hash(tag));
+    let api_url = config.api_url.trim_end_matches('/');
+
+    let url = format!("{}/repos/{}/releases", api_url, repo);
+    if let Some((releases, validators)) = fetch::<Vec<Release>>(&url, db, config).await? {
+        // Newest first. Pre-releases left out are not marked as seen, so
+        // they are sent once published as releases.
+        let mut releases = releases
+            .into_iter()
+            .filter(|x| !x.draft && (config.prereleases || !x.prerelease))
+            .filter(|x| {
+                !db.contains_key(release_key(&x.tag_name))
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+        if first {
+            for release in releases.iter().skip(1) {
+                db.insert(release_key(&release.tag_name), &[])?;
+            }
+            releases.truncate(1)
+        }
+
+        for release in releases.into_iter().rev() {
+            let seen = release_key(&release.tag_name);
+            let time = release
+                .published_at
+                .as_deref()
+                .and_then(|x| chrono::DateTime::parse_from_rfc3339(x).ok())
+                .map_or_else(now, |x| x.timestamp());
+            send(
+                tx,
+                Event::GitHubRelease {
+                    repo: repo.to_owned(),
+                    tag: release.tag_name,
+                    name: release.name.filter(|x| !x.is_empty()),
+                    body: release.body.filter(|x| !x.is_empty()),
+                    link: release.html_url,
+                    prerelease: release.prerelease,
+                    time,
+                },
+            )?;
+            // Marked as seen once queued, so it's sent on next check otherwise
+            db.insert(seen, &[])?;
+        }
+        validators.save(db, &get_hash(&url));
+    }
+
+    if !config.tags {
+        return Ok(());
+    }
+    let url = format!("{}/repos/{}/tags", api_url, repo);
+    if let Some((tags, validators)) = fetch::<Vec<Tag>>(&url, db, config).await? {
+        for tag in tags.into_iter().rev() {
+            let seen = tag_key(&tag.name);
+            if db.contains_key(&seen)? {
+                continue;
+            }
+            if first || db.contains_key(release_key(&tag.name))? {
+                db.insert(seen, &[])?;
+                continue;
+            }
+            send(
+                tx,
+                Event::GitHubRelease {
+                    link: format!(
+                        "{}/{}/releases/tag/{}",
+                        web_url(api_url),
+                        repo,
+                        percent_encoding::utf8_percent_encode(
+                            &tag.name,
+                            percent_encoding::NON_ALPHANUMERIC
+                        )
+                    ),
+                    repo: repo.to_owned(),
+                    tag: tag.name,
+                    name: None,
+                    body: None,
+                    prerelease: false,
+                    time: now(),
+                },
+            )?;
+            db.insert(seen, &[])?;
+        }
+        validators.save(db, &get_hash(&url));
+    }
+
+    Ok(())
+}
+
+fn send(tx: &Outbox, event: Event) -> Result<()> {
+    info!("New GitHub release: {}", event);
+    tx.send(event).wrap_err("Failed to queue event")?;
+    Ok(())
+}
+
+/// Get `url` from the API as JSON, or `None` if it's not modified since last
+/// request. Validators of the response are returned to be saved once it's
+/// handled.
+async fn fetch<T: DeserializeOwned>(
+    url: &str,
+    db: &Db,
+    config: &GitHubConfig,
+) -> Result<Option<(T, Validators)>> {
+    let id = get_hash(url);
+    let validators = Validators::load(db, &id);
+    let mut req = validators
+        .apply(get_client().get(url))
+        .header(ACCEPT, "application/vnd.github+json")
+        // Required by the API
+        .header(USER_AGENT, "caster");
+    if let Some(token) = &config.token {
+        req = req.bearer_auth(token)
+    }
+
+    let res = req
+        .send()
+        .await
+        .wrap_err_with(|| format!("Request failed: {}", url))?;
+    let status = res.status();
+    if status == StatusCode::NOT_MODIFIED {
+        debug!("Not modified: {}", url);
+        return Ok(None);
+    }
+    if !status.is_success() {
+        let text = res.text().await.unwrap_or_default();
+        return Err(eyre!("{}", text).wrap_err(format!(
+            "Unsuccessful response from server (Code: {})",
+            status
+        )));
+    }
+
+    let validators = Validators::from_headers(res.headers());
+    let body = res
+        .json()
+        .await
+        .wrap_err_with(|| format!("Failed to parse response: {}", url))?;
+    Ok(Some((body, validators)))
+}
+
+/// Url of web pages of the API, e.g. `https://github.com` for
+/// `https://api.github.com`, or `https://example.com` for
+/// `https://example.com/api/v3` of GitHub Enterprise
+fn web_url(api_url: &str) -> String {
+    match api_url.strip_prefix("https://api.") {
+        Some(host) => format!("https://{}", host),
+        None => api_url.trim_end_matches("/api/v3").to_owned(),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
@@ -1,40 +1,32 @@
-mod_use::mod_use![feed, crates];
+mod_use::mod_use![schedule, feed, crates, github, git];
 
 use std::{sync::Arc, time::SystemTime};
 
-use color_eyre::{eyre::Context, Result};
+use color_eyre::Result;
 use futures::future::join_all;
 use log::error;
-use once_cell::sync::OnceCell;
-use sled::Db;
-use tokio::sync::broadcast;
+use serde::de::DeserializeOwned;
+use tokio::task::JoinHandle;
 
-use crate::{Config, Event};
+use crate::{Config, Outbox, Registry};
 
-pub type TX = broadcast::Sender<Event>;
-pub type RX = broadcast::Receiver<Event>;
+/// A source of [`Event`](crate::Event)s. Register implementations with
+/// [`Registry::caster`].
+pub trait Caster: 'static {
+    /// Name of config section, e.g. `caster_feed`
+    const NAME: &'static str;
 
-static DB: OnceCell<Db> = OnceCell::new();
+    type Config: DeserializeOwned + Send + 'static;
 
-pub fn get_db<'a>() -> &'a Db {
-    DB.get().expect("DB not initialized")
+    /// Start the caster, which should send new events to `tx`
+    fn run(tx: Outbox, config: Self::Config) -> JoinHandle<()>;
 }
 
-pub async fn run_casters(tx: TX, config: Arc<Config>) -> Result<TX> {
-    let db = sled::open(&config.db_path).wrap_err("Failed to open db")?;
-    drop(DB.set(db));
-
+pub async fn run_casters(tx: Outbox, config: Arc<Config>, registry: &Registry) -> Result<()> {
     let start = SystemTime::now();
 
-    let mut handles = vec![];
-
-    if let Some(ref rss) = config.caster_feed {
-        handles.push(feed::run_feed(tx.clone(), rss.clone()))
-    }
-
-    if let Some(ref crates) = config.caster_crates {
-        handles.push(crates::run_crates(tx.clone(), crates.clone()))
-    }
+    let handles = registry.spawn_casters(&tx, &config)?;
+    drop(tx);
 
     join_all(handles).await.into_iter().for_each(|res| {
         if let Err(e) = res {
@@ -43,8 +35,8 @@ pub async fn run_casters(tx: TX, config: Arc<Config>) -> Result<TX> {
     });
 
     if start.elapsed()?.as_millis() < 500 {
-        log::warn!("Caster shutting down too quickly, did you config both casters right?")
+        log::warn!("Caster shutting down too quickly, did you config casters right?")
     }
 
-    Ok(tx)
+    Ok(())
 }
@@ -0,0 +1,158 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::header::{HeaderMap, CACHE_CONTROL, RETRY_AFTER};
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+
+/// Hints from publisher on when a feed should be fetched again
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hints {
+    /// `<ttl>` of RSS channel
+    pub ttl: Option<Duration>,
+    /// `max-age` of `Cache-Control`
+    pub max_age: Option<Duration>,
+    /// `Retry-After` of 429 or 503 responses
+    pub retry_after: Option<Duration>,
+    /// `<skipHours>` of RSS channel, in UTC, 0 to 23
+    pub skip_hours: Vec<u8>,
+    /// `<skipDays>` of RSS channel, 0 (Monday) to 6 (Sunday)
+    pub skip_days: Vec<u8>,
+}
+
+impl Hints {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        static MAX_AGE: Lazy<Regex> = Lazy::new(|| Regex::new(r"max-age\s*=\s*(\d+)").unwrap());
+
+        let max_age = headers
+            .get(CACHE_CONTROL)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| MAX_AGE.captures(x))
+            .and_then(|x| x[1].parse().ok())
+            .map(Duration::from_secs);
+
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| {
+                x.trim().parse().map(Duration::from_secs).ok().or_else(|| {
+                    let time = chrono::DateTime::parse_from_rfc2822(x).ok()?;
+                    (time.timestamp() - chrono::Utc::now().timestamp())
+                        .try_into()
+                        .ok()
+                        .map(Duration::from_secs)
+                })
+            });
+
+        Self {
+            max_age,
+            retry_after,
+            ..Self::default()
+        }
+    }
+
+    /// Read `<ttl>`, `<skipHours>` and `<skipDays>` from RSS body
+    pub fn with_body(mut self, ttl: Option<u32>, body: &[u8]) -> Self {
+        static SKIP_HOURS: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?s)<skipHours>(.*?)</skipHours>").unwrap());
+        static SKIP_DAYS: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?s)<skipDays>(.*?)</skipDays>").unwrap());
+        static HOUR: Lazy<Regex> = Lazy::new(|| Regex::new(r"<hour>\s*(\d+)\s*</hour>").unwrap());
+        static DAY: Lazy<Regex> = Lazy::new(|| Regex::new(r"<day>\s*(\w+)\s*</day>").unwrap());
+        const DAYS: [&str; 7] = [
+            "monday",
+            "tuesday",
+            "wednesday",
+            "thursday",
+            "friday",
+            "saturday",
+            "sunday",
+        ];
+
+        let body = String::from_utf8_lossy(body);
+
+        self.ttl = ttl.map(|x| Duration::from_secs(x as u64 * 60));
+        if let Some(hours) = SKIP_HOURS.captures(&body) {
+            self.skip_hours = HOUR
+                .captures_iter(&hours[1])
+                .filter_map(|x| x[1].parse().ok())
+                .filter(|x| *x < 24)
+                .collect();
+        }
+        if let Some(days) = SKIP_DAYS.captures(&body) {
+            self.skip_days = DAY
+                .captures_iter(&days[1])
+                .filter_map(|x| {
+                    let day = x[1].to_lowercase();
+                    DAYS.iter().position(|x| *x == day).map(|x| x as u8)
+                })
+                .collect();
+        }
+        self
+    }
+}
+
+/// Decides when to fetch a feed next. Feeds that update are fetched more
+/// often and those that don't less often, within `min` and `max`. Hints from
+/// publisher are respected.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Schedule {
+    pub fn new(interval: Duration, min: Duration, max: Duration) -> Self {
+        let max = max.max(min);
+        Self {
+            min,
+            max,
+            current: interval.clamp(min, max),
+        }
+    }
+
+    /// Delay before next fetch, given whether last fetch found any update
+    pub fn next(&mut self, updated: bool, hints: &Hints, now: SystemTime) -> Duration {
+        self.current = if updated {
+            self.current / 2
+        } else {
+            self.current.mul_f64(1.5)
+        }
+        .clamp(self.min, self.max);
+
+        if let Some(retry_after) = hints.retry_after {
+            return retry_after.max(self.current);
+        }
+
+        let hinted = hints.ttl.max(hints.max_age).unwrap_or_default();
+        let delay = self.current.max(hinted.min(self.max));
+
+        delay + skipped(now + delay, &hints.skip_hours, &hints.skip_days)
+    }
+}
+
+/// Time from `at` until the first hour that is not skipped
+fn skipped(at: SystemTime, skip_hours: &[u8], skip_days: &[u8]) -> Duration {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut hour = secs / SECONDS_PER_HOUR;
+
+    // 1970-01-01 is Thursday
+    let is_skipped = |hour: u64| {
+        skip_hours.contains(&((hour % 24) as u8))
+            || skip_days.contains(&(((hour / 24 + 3) % 7) as u8))
+    };
+
+    if !is_skipped(hour) {
+        return Duration::ZERO;
+    }
+    for _ in 0..24 * 7 {
+        hour += 1;
+        if !is_skipped(hour) {
+            return Duration::from_secs(hour * SECONDS_PER_HOUR - secs);
+        }
+    }
+    // Every hour is skipped, ignore
+    Duration::ZERO
+}
@@ -1,11 +1,18 @@
-use std::path::PathBuf;
+use std::{collections::BTreeMap, path::PathBuf};
 
-use color_eyre::{eyre::bail, Result};
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
 use figment::{
     providers::{Env, Format, Toml},
+    value::Value,
     Figment,
 };
-use serde::{Deserialize, Serialize};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{serde_regex, Consumer, RetryConfig, Route, Target, TelegramConsumer, TemplateConfig};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -21,27 +28,158 @@ pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
 
-    /// Config of feed caster
-    pub caster_feed: Option<FeedConfig>,
-
-    /// Config of crates.io caster
-    pub caster_crates: Option<CratesConfig>,
+    /// Routing rules between casters and consumers. Without any rule, every
+    /// event is sent to every consumer.
+    #[serde(default)]
+    pub routes: Vec<Route>,
 
-    /// Config of telegram consumer
-    pub consumer_telegram: Option<TelegramConfig>,
+    /// Config sections of casters and consumers, keyed by their name (e.g.
+    /// `caster_feed`). Each section is deserialized by the caster or consumer
+    /// registered under that name.
+    #[serde(flatten)]
+    pub sections: BTreeMap<String, Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FeedConfig {
-    pub urls: Vec<String>,
+    /// Feeds to fetch, either plain urls or tables of [`FeedSource`]
+    #[serde(alias = "urls")]
+    pub feeds: Vec<FeedItem>,
 
-    /// How old a newly seen entry will be ignored
+    /// How old a newly seen entry will be ignored, unless set by feed
     #[serde(default = "default_feed_ignore_days")]
     pub ignore_days: u64,
 
-    /// Interval between requests, in second
+    /// Interval between requests, in second, unless set by feed
     #[serde(default = "default_feed_interval")]
     pub interval: f64,
+
+    /// Min interval between requests of busy feeds, in second, unless set by
+    /// feed. Default to `interval`.
+    pub min_interval: Option<f64>,
+
+    /// Max interval between requests of feeds that rarely update, or of which
+    /// publisher asks for less requests, in second, unless set by feed
+    #[serde(default = "default_feed_max_interval")]
+    pub max_interval: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FeedItem {
+    Url(String),
+    Source(Box<FeedSource>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedSource {
+    pub url: String,
+
+    /// Name of feed, shown in messages
+    pub name: Option<String>,
+
+    /// Interval between requests, in second
+    pub interval: Option<f64>,
+
+    /// Min interval between requests, in second
+    pub min_interval: Option<f64>,
+
+    /// Max interval between requests, in second
+    pub max_interval: Option<f64>,
+
+    /// How old a newly seen entry will be ignored
+    pub ignore_days: Option<u64>,
+
+    /// Extra headers sent with requests
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    pub auth: Option<FeedAuth>,
+
+    /// Telegram chats to send entries of this feed to, in place of chats in
+    /// config of telegram consumer, unless routes send them elsewhere. Other
+    /// consumers are not affected.
+    #[serde(default, alias = "targets")]
+    pub chats: Vec<Target>,
+
+    /// Max text length of content, 0 means unlimited
+    pub content_max_length: Option<usize>,
+
+    /// Only entries with title or content matching this regex are sent
+    #[serde(default, with = "serde_regex")]
+    pub include: Option<Regex>,
+
+    /// Entries with title or content matching this regex are not sent
+    #[serde(default, with = "serde_regex")]
+    pub exclude: Option<Regex>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FeedAuth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
+}
+
+impl FeedConfig {
+    /// Routes sending entries of feeds with chats to those telegram chats, in
+    /// place of chats in config of telegram consumer
+    pub fn routes(&self) -> Vec<Route> {
+        self.sources()
+            .into_iter()
+            .filter(|x| !x.chats.is_empty())
+            .map(|x| Route {
+                feed: Some(x.url),
+                consumers: vec![TelegramConsumer::NAME.to_owned()],
+                targets: x.chats,
+                ..Route::default()
+            })
+            .collect()
+    }
+
+    /// All feeds, with unset options filled with those of this config
+    pub fn sources(&self) -> Vec<FeedSource> {
+        self.feeds
+            .iter()
+            .map(|item| {
+                let mut source = match item {
+                    FeedItem::Url(url) => FeedSource::new(url),
+                    FeedItem::Source(source) => source.as_ref().clone(),
+                };
+                let interval = *source.interval.get_or_insert(self.interval);
+                source
+                    .min_interval
+                    .get_or_insert(self.min_interval.unwrap_or(interval));
+                source.max_interval.get_or_insert(self.max_interval);
+                source.ignore_days.get_or_insert(self.ignore_days);
+                source
+            })
+            .collect()
+    }
+}
+
+impl FeedSource {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            name: None,
+            interval: None,
+            min_interval: None,
+            max_interval: None,
+            ignore_days: None,
+            headers: BTreeMap::new(),
+            auth: None,
+            chats: vec![],
+            content_max_length: None,
+            include: None,
+            exclude: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -55,14 +193,504 @@ pub struct CratesConfig {
     pub interval: f64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitHubConfig {
+    /// Repositories to watch, as `owner/name`
+    pub repos: Vec<String>,
+
+    /// Access token, for private repositories and a higher rate limit
+    pub token: Option<String>,
+
+    /// Base url of REST API, e.g. of a GitHub Enterprise server
+    #[serde(default = "default_github_api_url")]
+    pub api_url: String,
+
+    /// Whether pre-releases are sent
+    #[serde(default)]
+    pub prereleases: bool,
+
+    /// Whether tags without a release are sent as well
+    #[serde(default)]
+    pub tags: bool,
+
+    /// Interval between checks of each repository, in second. Unchanged
+    /// repositories are answered with 304, which don't count towards rate
+    /// limit.
+    #[serde(default = "default_github_interval")]
+    pub interval: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitConfig {
+    pub repos: Vec<GitRepo>,
+
+    /// Directory to keep bare clones of repositories in
+    #[serde(default = "default_git_cache_dir")]
+    pub cache_dir: String,
+
+    /// Interval between fetches of each repository, in second
+    #[serde(default = "default_git_interval")]
+    pub interval: f64,
+
+    /// Max number of new commits of a branch sent at a fetch, the latest ones
+    /// are kept
+    #[serde(default = "default_git_max_commits")]
+    pub max_commits: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitRepo {
+    /// Url of remote, e.g. `https://github.com/rust-lang/rust.git` or
+    /// `file:///srv/git/foo.git`
+    pub url: String,
+
+    /// Name of repository, shown in messages. Default to url.
+    pub name: Option<String>,
+
+    /// Branches to watch for new commits, all branches if empty
+    #[serde(default)]
+    pub branches: Vec<String>,
+
+    /// Whether new tags are sent
+    #[serde(default = "default_git_tags")]
+    pub tags: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TelegramConfig {
     pub api_token: String,
-    pub chats: Vec<i64>,
+
+    /// Chat IDs or usernames of channels (e.g. `@channel`) to send to
+    pub chats: Vec<Target>,
+
+    /// Max text length of content
+    #[serde(default = "default_telegram_content_max_length")]
+    pub content_max_length: usize,
+
+    /// How failed messages are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of messages by kind of event, in HTML supported by telegram.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscordConfig {
+    /// Webhook urls to post to. Routes target some of them by index, starting
+    /// from 0, as urls are secret.
+    pub webhooks: Vec<String>,
+
+    /// Override username of webhooks
+    pub username: Option<String>,
+
+    /// Override avatar of webhooks
+    pub avatar_url: Option<String>,
+
+    /// Max text length of content, 0 for unlimited. Descriptions of embeds
+    /// are cut at 4096 characters regardless.
+    #[serde(default = "default_discord_content_max_length")]
+    pub content_max_length: usize,
+
+    /// How failed messages are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of description of embeds by kind of event, in markdown.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlackConfig {
+    /// Incoming webhook urls to post to. Routes target some of them by index,
+    /// starting from 0, as urls are secret.
+    pub webhooks: Vec<String>,
+
+    /// Max text length of content, 0 for unlimited. Sections are cut at 3000
+    /// characters regardless.
+    #[serde(default = "default_slack_content_max_length")]
+    pub content_max_length: usize,
+
+    /// How failed messages are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of section of messages by kind of event, in mrkdwn.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatrixConfig {
+    /// Base url of homeserver, e.g. `https://matrix.org`
+    pub homeserver: String,
+
+    /// Access token of the account to send as
+    pub access_token: String,
+
+    /// IDs of rooms to send to, e.g. `!abcdefg:matrix.org`
+    pub rooms: Vec<String>,
+
+    /// `msgtype` of messages, `m.text` or `m.notice`
+    #[serde(default = "default_matrix_msgtype")]
+    pub msgtype: String,
 
     /// Max text length of content
     #[serde(default = "default_telegram_content_max_length")]
     pub content_max_length: usize,
+
+    /// How failed messages are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of messages by kind of event, in the same HTML as telegram.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    /// Hostname of SMTP server
+    pub host: String,
+
+    /// Port of SMTP server, defaults to 587 for `starttls`, 465 for `tls` and
+    /// 25 for `none`
+    pub port: Option<u16>,
+
+    /// How connections are secured. Value: starttls, tls, none.
+    #[serde(default)]
+    pub tls: SmtpTls,
+
+    pub username: Option<String>,
+
+    pub password: Option<String>,
+
+    /// Sender address, e.g. `Caster <caster@example.com>`
+    pub from: String,
+
+    /// Addresses to send to. Routes can target other addresses.
+    pub to: Vec<String>,
+
+    /// If set, events are collected for this many seconds and sent as one
+    /// digest to each recipient, instead of one email per event
+    pub digest_interval: Option<f64>,
+
+    /// Max text length of content, 0 for unlimited
+    #[serde(default)]
+    pub content_max_length: usize,
+
+    /// How failed emails are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of emails by kind of event, in the same HTML as telegram.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// Urls to POST to. Routes can target other urls.
+    pub urls: Vec<String>,
+
+    /// Extra headers of requests
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// If set, payload is signed with HMAC-SHA256 using this secret, sent in
+    /// `signature_header` as `sha256=<hex digest>`
+    pub secret: Option<String>,
+
+    #[serde(default = "default_webhook_signature_header")]
+    pub signature_header: String,
+
+    /// Timeout of each request, in second
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: f64,
+
+    /// How failed requests are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of bodies by kind of event. Events of kinds without a
+    /// template are sent as JSON, see [`EventRecord`](crate::EventRecord).
+    #[serde(default)]
+    pub templates: TemplateConfig,
+
+    /// `Content-Type` of templated bodies
+    #[serde(default = "default_webhook_content_type")]
+    pub content_type: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonlConfig {
+    /// File to append events to, `-` for stdout. Routes can target other
+    /// files.
+    #[serde(default = "default_jsonl_path")]
+    pub path: String,
+
+    /// Size of file in bytes, beyond which it's rotated to `<path>.1`,
+    /// `<path>.2` and so on. Never rotated if not set.
+    pub max_size: Option<u64>,
+
+    /// How many rotated files are kept
+    #[serde(default = "default_jsonl_max_files")]
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedServerConfig {
+    /// Address to listen on
+    #[serde(default = "default_feed_server_listen")]
+    pub listen: String,
+
+    /// Public url of server, used in links of feeds. Default to
+    /// `http://<listen>`.
+    pub base_url: Option<String>,
+
+    /// Title of feeds
+    #[serde(default = "default_feed_server_title")]
+    pub title: String,
+
+    /// How many latest events are kept and served
+    #[serde(default = "default_feed_server_max_entries")]
+    pub max_entries: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NtfyConfig {
+    /// Urls of topics to publish to, e.g. `https://ntfy.sh/mytopic`. Routes
+    /// target some of them by index, starting from 0, as topics are secret.
+    pub topics: Vec<String>,
+
+    /// Access token of protected topics
+    pub token: Option<String>,
+
+    /// Priority of messages, 1 (min) to 5 (max). Default to server default.
+    pub priority: Option<u8>,
+
+    /// Tags of messages, which are shown as emojis if known by ntfy
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Max text length of content, 0 for unlimited
+    #[serde(default = "default_ntfy_content_max_length")]
+    pub content_max_length: usize,
+
+    /// How failed messages are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of messages by kind of event, in plain text.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GotifyConfig {
+    /// Base url of server, e.g. `https://gotify.example.com`
+    pub server: String,
+
+    /// Tokens of applications to send as. Routes target some of them by index,
+    /// starting from 0, as tokens are secret.
+    pub tokens: Vec<String>,
+
+    /// Priority of messages. Default to priority of application.
+    pub priority: Option<u8>,
+
+    /// Whether messages are rendered as markdown by clients
+    #[serde(default = "default_gotify_markdown")]
+    pub markdown: bool,
+
+    /// Max text length of content, 0 for unlimited
+    #[serde(default = "default_gotify_content_max_length")]
+    pub content_max_length: usize,
+
+    /// How failed messages are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of messages by kind of event, in markdown if `markdown` is
+    /// set. `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IrcConfig {
+    /// Host of server, e.g. `irc.libera.chat`
+    pub server: String,
+
+    /// Port of server. Default to 6697 with TLS, 6667 otherwise.
+    pub port: Option<u16>,
+
+    #[serde(default = "default_irc_tls")]
+    pub tls: bool,
+
+    pub nickname: String,
+
+    /// Default to `nickname`
+    pub username: Option<String>,
+
+    /// Default to `nickname`
+    pub realname: Option<String>,
+
+    /// Password of server
+    pub password: Option<String>,
+
+    /// Account to log in with SASL PLAIN
+    pub sasl: Option<SaslConfig>,
+
+    /// Password to identify to NickServ with, once connected. Not used when
+    /// logged in with SASL.
+    pub nickserv_password: Option<String>,
+
+    /// Channels to join and post to. Routes can target other channels.
+    pub channels: Vec<String>,
+
+    /// Min interval between messages on average, in second
+    #[serde(default = "default_irc_throttle_interval")]
+    pub throttle_interval: f64,
+
+    /// How many messages can be sent at once before being throttled
+    #[serde(default = "default_irc_throttle_burst")]
+    pub throttle_burst: u32,
+
+    /// Delay before reconnecting after disconnected, in second
+    #[serde(default = "default_irc_reconnect_delay")]
+    pub reconnect_delay: f64,
+
+    /// How joining channels is retried before messages to them are saved as
+    /// dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of messages by kind of event. Messages are joined into one
+    /// line.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaslConfig {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MastodonConfig {
+    /// Base url of instance, e.g. `https://mastodon.social`
+    pub instance: String,
+
+    /// Access token of application, with `write:statuses` scope
+    pub access_token: String,
+
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Content warning shown in place of statuses until expanded
+    pub content_warning: Option<String>,
+
+    /// ISO 639 language code of statuses
+    pub language: Option<String>,
+
+    /// Max characters of statuses allowed by instance. Longer ones are cut,
+    /// keeping the trailing link if any.
+    #[serde(default = "default_mastodon_max_characters")]
+    pub max_characters: usize,
+
+    /// Max text length of content, 0 for unlimited
+    #[serde(default = "default_mastodon_content_max_length")]
+    pub content_max_length: usize,
+
+    /// How failed statuses are retried before saved as dead letters
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Templates of statuses by kind of event, in plain text.
+    /// `content_max_length` is available as a variable.
+    #[serde(default)]
+    pub templates: TemplateConfig,
+}
+
+/// Who can see a status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    /// Public, but not shown in public timelines
+    Unlisted,
+    /// Followers only
+    Private,
+    /// Mentioned users only
+    Direct,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    /// Host of broker
+    pub host: String,
+
+    /// Port of broker. Default to 8883 with TLS, 1883 otherwise.
+    pub port: Option<u16>,
+
+    #[serde(default)]
+    pub tls: bool,
+
+    pub username: Option<String>,
+
+    /// Requires `username`
+    pub password: Option<String>,
+
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+
+    /// Prefix of topics. Events are published to `<prefix>/feed/<feed>`,
+    /// where `<feed>` is name or hash of url of feed, and
+    /// `<prefix>/crates/<name>`. Must not be empty. Routes can target other
+    /// topics, without wildcards.
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+
+    /// Quality of service. Value: 0 (at most once), 1 (at least once), 2
+    /// (exactly once).
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+
+    /// Whether broker should keep last event of each topic for new
+    /// subscribers
+    #[serde(default)]
+    pub retain: bool,
+
+    /// Interval of pings to broker when idle, in second
+    #[serde(default = "default_mqtt_keep_alive")]
+    pub keep_alive: u16,
+
+    /// Delay before reconnecting after disconnected, in second
+    #[serde(default = "default_mqtt_reconnect_delay")]
+    pub reconnect_delay: f64,
+}
+
+/// How connections to SMTP server are secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Upgrade a plain connection with `STARTTLS`
+    #[default]
+    Starttls,
+    /// Implicit TLS
+    Tls,
+    /// No encryption, only for local servers
+    None,
 }
 
 impl Config {
@@ -82,6 +710,18 @@ impl Config {
     pub fn new() -> Result<Self> {
         Self::with_path(None)
     }
+
+    /// Deserialize config section `name`, or `None` if it's absent
+    pub fn section<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        self.sections
+            .get(name)
+            .map(|value| {
+                value
+                    .deserialize()
+                    .wrap_err_with(|| format!("Invalid config section `{}`", name))
+            })
+            .transpose()
+    }
 }
 
 fn default_log_level() -> String {
@@ -100,10 +740,138 @@ fn default_feed_interval() -> f64 {
     60.0
 }
 
+fn default_feed_max_interval() -> f64 {
+    60.0 * 60.0
+}
+
 fn default_feed_ignore_days() -> u64 {
     30
 }
 
+fn default_github_api_url() -> String {
+    "https://api.github.com".to_owned()
+}
+
+fn default_github_interval() -> f64 {
+    60.0 * 5.0
+}
+
+fn default_git_cache_dir() -> String {
+    "/tmp/caster/git".to_owned()
+}
+
+fn default_git_interval() -> f64 {
+    60.0 * 5.0
+}
+
+fn default_git_max_commits() -> usize {
+    20
+}
+
+fn default_git_tags() -> bool {
+    true
+}
+
 fn default_telegram_content_max_length() -> usize {
     100
 }
+
+fn default_discord_content_max_length() -> usize {
+    500
+}
+
+fn default_slack_content_max_length() -> usize {
+    500
+}
+
+fn default_matrix_msgtype() -> String {
+    "m.text".to_owned()
+}
+
+fn default_webhook_signature_header() -> String {
+    "X-Caster-Signature".to_owned()
+}
+
+fn default_webhook_timeout() -> f64 {
+    10.0
+}
+
+fn default_webhook_content_type() -> String {
+    "application/json".to_owned()
+}
+
+fn default_jsonl_path() -> String {
+    "-".to_owned()
+}
+
+fn default_jsonl_max_files() -> usize {
+    5
+}
+
+fn default_feed_server_listen() -> String {
+    "127.0.0.1:8080".to_owned()
+}
+
+fn default_feed_server_title() -> String {
+    "Caster".to_owned()
+}
+
+fn default_feed_server_max_entries() -> usize {
+    100
+}
+
+fn default_ntfy_content_max_length() -> usize {
+    500
+}
+
+fn default_gotify_markdown() -> bool {
+    true
+}
+
+fn default_gotify_content_max_length() -> usize {
+    500
+}
+
+fn default_irc_tls() -> bool {
+    true
+}
+
+fn default_irc_throttle_interval() -> f64 {
+    2.0
+}
+
+fn default_irc_throttle_burst() -> u32 {
+    4
+}
+
+fn default_irc_reconnect_delay() -> f64 {
+    30.0
+}
+
+fn default_mastodon_max_characters() -> usize {
+    500
+}
+
+fn default_mastodon_content_max_length() -> usize {
+    300
+}
+
+fn default_mqtt_client_id() -> String {
+    "caster".to_owned()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "caster".to_owned()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+fn default_mqtt_keep_alive() -> u16 {
+    60
+}
+
+fn default_mqtt_reconnect_delay() -> f64 {
+    10.0
+}
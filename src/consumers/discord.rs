@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use reqwest::{header::HeaderMap, StatusCode};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    by_index, fan_out, get_client, get_db, hide_url, index_targets, permanent, retry_after,
+    retry_after_of, truncate_chars, ts_to_systemtime, Consumer, DeadLetters, DiscordConfig, Event,
+    EventKind, Inbox, RateLimiter, Target, Templates,
+};
+
+/// Max length of title of an embed
+const MAX_TITLE_LENGTH: usize = 256;
+
+/// Max length of description of an embed
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+/// Consumer that posts events to discord channels as embeds via webhooks
+pub struct DiscordConsumer;
+
+impl DiscordConsumer {
+    /// Layouts of descriptions when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"{% if content -%}
+{% set text = content | html2text | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"New update: **{{ name }}** {{ vers }}
+{%- if yanked %}
+Yanked: true
+{%- endif %}
+{%- if links %}
+Links: {{ links }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"{% if body -%}
+{% set text = body | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::Git,
+            r#"`{{ commit | truncate(length=7, end="") }}`{% if branch %} on {{ branch }}{% endif %} by {{ author }}
+{%- if message %}
+{% set text = message | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for DiscordConsumer {
+    type Config = DiscordConfig;
+
+    const NAME: &'static str = "consumer_discord";
+
+    fn run(inbox: Inbox, config: DiscordConfig) -> JoinHandle<()> {
+        run_discord(inbox, config)
+    }
+}
+
+pub fn run_discord(mut inbox: Inbox, config: DiscordConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(DiscordConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        let webhooks = index_targets(&config.webhooks);
+        let limits = Limits::default();
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let description = match templates.render(&envelope.event, &vars) {
+                Ok(x) => x,
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&webhooks), e);
+                    continue;
+                }
+            };
+            let mut payload = json!({ "embeds": [embed(&envelope.event, &description)] });
+            if let Some(username) = &config.username {
+                payload["username"] = username.as_str().into();
+            }
+            if let Some(avatar_url) = &config.avatar_url {
+                payload["avatar_url"] = avatar_url.as_str().into();
+            }
+            debug!("Payload: {}", payload);
+
+            let targets = envelope.targets.resolve(&webhooks);
+            let failed = fan_out(targets, "Message posted to webhook", |webhook| {
+                post_to(webhook, &payload, &config, &limits)
+            })
+            .await;
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// Build an embed of `event`
+fn embed(event: &Event, description: &str) -> Value {
+    let description = truncate_chars(description, MAX_DESCRIPTION_LENGTH);
+    match event {
+        Event::Feed {
+            name,
+            time,
+            title,
+            link,
+            ..
+        } => {
+            let title = title.as_deref().or(name.as_deref()).unwrap_or("Feed");
+            let mut embed = json!({
+                "title": truncate_chars(title, MAX_TITLE_LENGTH),
+                "url": link,
+                "description": description,
+                "timestamp": humantime::format_rfc3339(ts_to_systemtime(*time as u64)).to_string(),
+            });
+            if let Some(name) = name {
+                embed["author"] = json!({ "name": truncate_chars(name, MAX_TITLE_LENGTH) });
+            }
+            embed
+        }
+        Event::CratesIo {
+            name, vers, yanked, ..
+        } => json!({
+            "title": truncate_chars(&format!("{} {}", name, vers), MAX_TITLE_LENGTH),
+            "url": format!("https://crates.io/crates/{}", name),
+            "description": description,
+            "footer": { "text": "Crates.io" },
+            // Red for yanked versions, orange otherwise
+            "color": if *yanked { 0xe74c3c } else { 0xe67e22 },
+        }),
+        Event::GitHubRelease {
+            repo,
+            tag,
+            name,
+            link,
+            time,
+            ..
+        } => json!({
+            "title": truncate_chars(name.as_deref().unwrap_or(tag), MAX_TITLE_LENGTH),
+            "url": link,
+            "description": description,
+            "author": { "name": truncate_chars(repo, MAX_TITLE_LENGTH) },
+            "footer": { "text": "GitHub" },
+            "timestamp": humantime::format_rfc3339(ts_to_systemtime(*time as u64)).to_string(),
+        }),
+        Event::Git {
+            repo,
+            tag,
+            message,
+            time,
+            ..
+        } => json!({
+            "title": truncate_chars(
+                tag.as_deref().unwrap_or_else(|| message.lines().next().unwrap_or_default()),
+                MAX_TITLE_LENGTH
+            ),
+            "description": description,
+            "author": { "name": truncate_chars(repo, MAX_TITLE_LENGTH) },
+            "footer": { "text": "Git" },
+            "timestamp": humantime::format_rfc3339(ts_to_systemtime(*time as u64)).to_string(),
+        }),
+    }
+}
+
+/// Rate limits of each webhook, following `X-RateLimit-*` headers
+#[derive(Debug, Default)]
+struct Limits {
+    webhooks: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl Limits {
+    fn get(&self, webhook: &str) -> Arc<RateLimiter> {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .entry(webhook.to_owned())
+            .or_insert_with(|| Arc::new(RateLimiter::per(5, Duration::from_secs(2))))
+            .clone()
+    }
+}
+
+/// Post `payload` to `webhook`, by index in config, with retries
+async fn post_to(
+    webhook: &Target,
+    payload: &Value,
+    config: &DiscordConfig,
+    limits: &Limits,
+) -> Result<()> {
+    let url = by_index(&config.webhooks, "webhooks", webhook)?;
+    let limiter = limits.get(url);
+    config
+        .retry
+        .retry(|| async {
+            limiter.wait().await;
+            let res = post(url, payload, &limiter).await;
+            if let Some(delay) = res.as_ref().err().and_then(retry_after_of) {
+                limiter.pause(delay)
+            }
+            res
+        })
+        .await
+}
+
+async fn post(url: &str, payload: &Value, limiter: &RateLimiter) -> Result<()> {
+    let res = get_client()
+        .post(url)
+        .query(&[("wait", "true")])
+        .json(payload)
+        .send()
+        .await
+        .map_err(hide_url)
+        .wrap_err("Failed to request discord webhook")?;
+    let status = res.status();
+    let headers = res.headers().clone();
+
+    // Bucket is exhausted, wait for it to reset before next request
+    if header(&headers, "x-ratelimit-remaining") == Some(0.0) {
+        if let Some(reset_after) = header(&headers, "x-ratelimit-reset-after")
+            .and_then(|x| Duration::try_from_secs_f64(x).ok())
+        {
+            limiter.pause(reset_after)
+        }
+    }
+
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let text = res.text().await.unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let delay = serde_json::from_str::<Value>(&text)
+            .ok()
+            .and_then(|x| x["retry_after"].as_f64())
+            .or_else(|| header(&headers, "retry-after"))
+            .and_then(|x| Duration::try_from_secs_f64(x).ok())
+            .unwrap_or(Duration::from_secs(1));
+        Err(retry_after(e, delay))
+    } else if status.is_client_error() {
+        Err(permanent(e))
+    } else {
+        Err(e)
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
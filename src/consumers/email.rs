@@ -0,0 +1,280 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use color_eyre::{
+    eyre::{bail, Context},
+    Report, Result,
+};
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use log::{debug, info, warn};
+use serde_json::json;
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{
+    check_secs, get_db, permanent, Consumer, DeadLetters, EmailConfig, Envelope, Event, Inbox,
+    Route, SmtpTls, Target, TelegramConsumer, Templates,
+};
+
+type Mailer = AsyncSmtpTransport<Tokio1Executor>;
+
+/// Consumer that sends events as emails over SMTP, one per event or in
+/// digests
+pub struct EmailConsumer;
+
+impl Consumer for EmailConsumer {
+    type Config = EmailConfig;
+
+    const NAME: &'static str = "consumer_email";
+
+    fn validate(config: &EmailConfig, routes: &[Route]) -> Result<()> {
+        config
+            .from
+            .parse::<Mailbox>()
+            .wrap_err_with(|| format!("Invalid sender address `{}`", config.from))?;
+        if let Some(interval) = config.digest_interval {
+            check_secs("digest_interval", interval)?
+        }
+        for to in config.to.iter() {
+            check_address(to)?
+        }
+        // Other routes may target chats of other consumers, which are only
+        // checked once routed here
+        for route in routes
+            .iter()
+            .filter(|x| x.consumers.iter().any(|x| x == Self::NAME))
+        {
+            for target in route.targets.iter() {
+                match target {
+                    Target::Name(to) => check_address(to)?,
+                    Target::Id(id) => bail!("Invalid recipient address `{}`", id),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run(inbox: Inbox, config: EmailConfig) -> JoinHandle<()> {
+        run_email(inbox, config)
+    }
+}
+
+/// An event rendered to be sent to some recipients
+struct Rendered {
+    envelope: Envelope,
+    subject: String,
+    html: String,
+    recipients: Vec<Target>,
+}
+
+pub fn run_email(mut inbox: Inbox, config: EmailConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mailer = match mailer(&config) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        // Same HTML as telegram
+        let templates = match Templates::new(TelegramConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        let recipients = config
+            .to
+            .iter()
+            .cloned()
+            .map(Target::Name)
+            .collect::<Vec<_>>();
+
+        let render = |envelope: Envelope| {
+            info!("New event: {}", envelope.event);
+            match templates.render(&envelope.event, &vars) {
+                Ok(html) => Some(Rendered {
+                    subject: subject(&envelope.event),
+                    html,
+                    recipients: envelope.targets.resolve(&recipients).to_vec(),
+                    envelope,
+                }),
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&recipients), e);
+                    None
+                }
+            }
+        };
+
+        let interval = match config.digest_interval {
+            Some(interval) => Duration::from_secs_f64(interval),
+            None => {
+                while let Some(envelope) = inbox.recv().await {
+                    if let Some(rendered) = render(envelope) {
+                        send_batch(vec![rendered], &mailer, &config, &dead_letters).await
+                    }
+                }
+                return;
+            }
+        };
+
+        // Digest of events since first one in batch
+        let mut batch = vec![];
+        let mut deadline = None;
+        loop {
+            tokio::select! {
+                res = inbox.recv() => match res {
+                    Some(envelope) => {
+                        batch.extend(render(envelope));
+                        deadline.get_or_insert_with(|| Instant::now() + interval);
+                    }
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    deadline = None;
+                    send_batch(std::mem::take(&mut batch), &mailer, &config, &dead_letters).await
+                }
+            }
+        }
+        if !batch.is_empty() {
+            send_batch(batch, &mailer, &config, &dead_letters).await
+        }
+    })
+}
+
+fn mailer(config: &EmailConfig) -> Result<Mailer> {
+    let mut builder = match config.tls {
+        SmtpTls::Starttls => Mailer::starttls_relay(&config.host),
+        SmtpTls::Tls => Mailer::relay(&config.host),
+        SmtpTls::None => Ok(Mailer::builder_dangerous(&config.host)),
+    }
+    .wrap_err("Failed to set up SMTP transport")?;
+    if let Some(port) = config.port {
+        builder = builder.port(port)
+    }
+    if let Some(username) = &config.username {
+        builder = builder.credentials(Credentials::new(
+            username.to_owned(),
+            config.password.clone().unwrap_or_default(),
+        ))
+    }
+    Ok(builder.build())
+}
+
+fn check_address(address: &str) -> Result<()> {
+    address
+        .parse::<Mailbox>()
+        .wrap_err_with(|| format!("Invalid recipient address `{}`", address))?;
+    Ok(())
+}
+
+fn subject(event: &Event) -> String {
+    match event {
+        Event::Feed { name, title, .. } => match (name, title) {
+            (Some(name), Some(title)) => format!("[{}] {}", name, title),
+            (None, Some(x)) | (Some(x), None) => x.to_owned(),
+            (None, None) => "New feed entry".to_owned(),
+        },
+        Event::CratesIo { name, vers, .. } => format!("[Crates.io] {} {}", name, vers),
+        Event::GitHubRelease {
+            repo, tag, name, ..
+        } => format!("[{}] {}", repo, name.as_deref().unwrap_or(tag)),
+        Event::Git {
+            repo, tag, message, ..
+        } => match tag {
+            Some(tag) => format!("[{}] Tag {}", repo, tag),
+            None => format!("[{}] {}", repo, message.lines().next().unwrap_or_default()),
+        },
+    }
+}
+
+/// Send each recipient one email of all events in `batch` for it, then save
+/// failed ones as dead letters and acknowledge the rest
+async fn send_batch(
+    batch: Vec<Rendered>,
+    mailer: &Mailer,
+    config: &EmailConfig,
+    dead_letters: &DeadLetters,
+) {
+    let mut by_recipient = BTreeMap::<_, Vec<_>>::new();
+    for rendered in batch.iter() {
+        for recipient in rendered.recipients.iter() {
+            by_recipient.entry(recipient).or_default().push(rendered);
+        }
+    }
+
+    let mut failed = BTreeMap::<u64, Vec<_>>::new();
+    for (recipient, events) in by_recipient {
+        let res = config
+            .retry
+            .retry(|| send(mailer, config, recipient, &events))
+            .await;
+        match res {
+            Ok(()) => info!("Email of {} events sent to {}", events.len(), recipient),
+            Err(e) => {
+                let e = format!("{:#}", e);
+                for rendered in events {
+                    failed
+                        .entry(rendered.envelope.id)
+                        .or_default()
+                        .push((recipient, e.clone()))
+                }
+            }
+        }
+    }
+
+    for rendered in batch.iter() {
+        let failed = failed.remove(&rendered.envelope.id).unwrap_or_default();
+        rendered.envelope.settle(dead_letters, failed);
+    }
+}
+
+async fn send(
+    mailer: &Mailer,
+    config: &EmailConfig,
+    recipient: &Target,
+    events: &[&Rendered],
+) -> Result<()> {
+    let subject = match events {
+        [rendered] => rendered.subject.clone(),
+        _ => format!("{} new events", events.len()),
+    };
+    let html = events
+        .iter()
+        .map(|x| x.html.replace('\n', "<br>\n"))
+        .collect::<Vec<_>>()
+        .join("\n<hr>\n");
+    let text = html2text::from_read(html.as_bytes(), 80);
+    let html = format!("<!DOCTYPE html>\n<html><body>\n{}\n</body></html>", html);
+
+    let email = Message::builder()
+        .from(config.from.parse().wrap_err("Invalid sender address")?)
+        .to(recipient
+            .to_string()
+            .parse()
+            .wrap_err("Invalid recipient address")
+            .map_err(permanent)?)
+        .subject(subject)
+        .multipart(MultiPart::alternative_plain_html(text, html))
+        .wrap_err("Failed to build email")?;
+    debug!("Sending email to {}", recipient);
+
+    match mailer.send(email).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.is_permanent() => Err(permanent(Report::new(e))),
+        Err(e) => Err(Report::new(e).wrap_err("Failed to send email")),
+    }
+}
@@ -0,0 +1,445 @@
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::SystemTime};
+
+use atom_syndication as atom;
+use chrono::{DateTime, FixedOffset};
+use color_eyre::{eyre::Context, Result};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sled::{Db, Tree};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{get_db, Consumer, Event, FeedServerConfig, Inbox, Target};
+
+/// Consumer that keeps latest events and serves them as Atom, RSS and JSON
+/// feeds over HTTP.
+///
+/// All events are served at `/feed.atom`, `/feed.rss` and `/feed.json`.
+/// Events routed to a target are also served under `/<target>/`, e.g.
+/// `/rust/feed.atom` for a route with `targets = [ "rust" ]`.
+pub struct FeedServerConsumer;
+
+impl Consumer for FeedServerConsumer {
+    type Config = FeedServerConfig;
+
+    const NAME: &'static str = "consumer_feed_server";
+
+    fn run(inbox: Inbox, config: FeedServerConfig) -> JoinHandle<()> {
+        run_feed_server(inbox, config)
+    }
+}
+
+/// An event kept by [`FeedServerConsumer`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Archived {
+    /// Id of event in outbox
+    pub id: u64,
+    pub event: Event,
+    /// Targets the event was routed to, serving as tags of feeds
+    pub tags: Vec<String>,
+    /// Unix timestamp of receiving
+    pub time: i64,
+}
+
+/// Latest events kept by [`FeedServerConsumer`], oldest first
+#[derive(Debug, Clone)]
+pub struct Archive {
+    tree: Tree,
+}
+
+impl Archive {
+    pub fn open(db: &Db) -> Result<Self> {
+        Ok(Self {
+            tree: db.open_tree("feed_server")?,
+        })
+    }
+
+    /// Keep `archived`, dropping oldest ones beyond `max_entries`
+    pub fn push(&self, archived: &Archived, max_entries: usize) -> Result<()> {
+        self.tree.insert(
+            archived.id.to_be_bytes(),
+            serde_json::to_vec(archived).wrap_err("Failed to serialize event")?,
+        )?;
+        while self.tree.len() > max_entries {
+            if self.tree.pop_min()?.is_none() {
+                break;
+            }
+        }
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    /// Latest events with `tag`, or all latest events, newest first
+    pub fn list(&self, tag: Option<&str>) -> Result<Vec<Archived>> {
+        let mut res = vec![];
+        for value in self.tree.iter().values().rev() {
+            let archived: Archived =
+                serde_json::from_slice(&value?).wrap_err("Failed to deserialize event")?;
+            if tag.is_none_or(|tag| archived.tags.iter().any(|x| x == tag)) {
+                res.push(archived)
+            }
+        }
+        Ok(res)
+    }
+}
+
+struct State {
+    archive: Archive,
+    config: FeedServerConfig,
+    base_url: String,
+}
+
+pub fn run_feed_server(mut inbox: Inbox, config: FeedServerConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let archive = match Archive::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open archive of feed server: {}", e);
+                return;
+            }
+        };
+        let addr: SocketAddr = match config.listen.parse() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Invalid address to listen on `{}`: {}", config.listen, e);
+                return;
+            }
+        };
+        let base_url = config
+            .base_url
+            .clone()
+            .unwrap_or_else(|| format!("http://{}", config.listen));
+        let state = Arc::new(State {
+            archive: archive.clone(),
+            config: config.clone(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+        });
+
+        let make_service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone()))) }
+        });
+        let server = match Server::try_bind(&addr) {
+            Ok(x) => x.serve(make_service),
+            Err(e) => {
+                warn!("Failed to listen on {}: {}", addr, e);
+                return;
+            }
+        };
+        info!("Serving feeds at {}/feed.atom", base_url);
+
+        // Serve until there will be no more events
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(server.with_graceful_shutdown(async {
+            stopped.await.ok();
+        }));
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let time = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64;
+            let archived = Archived {
+                id: envelope.id,
+                event: envelope.event.clone(),
+                tags: envelope
+                    .targets
+                    .resolve(&[])
+                    .iter()
+                    .map(Target::to_string)
+                    .collect(),
+                time,
+            };
+            match archive.push(&archived, config.max_entries) {
+                Ok(()) => {
+                    if let Err(e) = envelope.ack() {
+                        warn!("Failed to acknowledge event #{}: {}", envelope.id, e)
+                    }
+                }
+                Err(e) => warn!("Failed to keep event #{}: {:#}", envelope.id, e),
+            }
+        }
+
+        stop.send(()).ok();
+        if let Ok(Err(e)) = server.await {
+            warn!("Feed server failed: {}", e)
+        }
+    })
+}
+
+async fn handle(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
+    debug!("{} {}", req.method(), req.uri());
+    let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+    let (tag, file) = match path.trim_start_matches('/').rsplit_once('/') {
+        Some((tag, file)) => (Some(tag), file),
+        None => (None, path.trim_start_matches('/')),
+    };
+
+    let res = match (req.method(), file) {
+        (&Method::GET | &Method::HEAD, "feed.atom" | "feed.rss" | "feed.json") => {
+            match render(&state, tag, req.uri().path()) {
+                Ok((body, content_type)) => Response::builder()
+                    .header(CONTENT_TYPE, content_type)
+                    .body(Body::from(body)),
+                Err(e) => {
+                    warn!("Failed to render {}: {:#}", path, e);
+                    Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::empty())
+                }
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(res.unwrap_or_default())
+}
+
+/// Render feed at `path` of events with `tag`, returning it with its content
+/// type
+fn render(state: &State, tag: Option<&str>, path: &str) -> Result<(String, &'static str)> {
+    let entries = state
+        .archive
+        .list(tag)?
+        .iter()
+        .map(Entry::from)
+        .collect::<Vec<_>>();
+    let title = match tag {
+        Some(tag) => format!("{} - {}", state.config.title, tag),
+        None => state.config.title.clone(),
+    };
+    let url = format!("{}{}", state.base_url, path);
+    let feed = FeedInfo {
+        title,
+        home: format!("{}/", url.rsplit_once('/').map_or(url.as_str(), |x| x.0)),
+        url: url.clone(),
+        updated: entries
+            .first()
+            .map_or_else(|| to_datetime(0), |x| x.updated),
+    };
+
+    Ok(match path.rsplit('/').next().unwrap_or_default() {
+        "feed.atom" => (atom(&feed, &entries), "application/atom+xml; charset=utf-8"),
+        "feed.rss" => (rss(&feed, &entries), "application/rss+xml; charset=utf-8"),
+        _ => (
+            json_feed(&feed, &entries),
+            "application/feed+json; charset=utf-8",
+        ),
+    })
+}
+
+struct FeedInfo {
+    title: String,
+    home: String,
+    url: String,
+    updated: DateTime<FixedOffset>,
+}
+
+/// An event as entry of feeds
+struct Entry {
+    id: String,
+    title: String,
+    link: Option<String>,
+    html: Option<String>,
+    author: Option<String>,
+    updated: DateTime<FixedOffset>,
+}
+
+impl From<&Archived> for Entry {
+    fn from(archived: &Archived) -> Self {
+        let id = format!("urn:caster:event:{}", archived.id);
+        match &archived.event {
+            Event::Feed {
+                name,
+                time,
+                content,
+                title,
+                link,
+                ..
+            } => Self {
+                id,
+                title: title.clone().unwrap_or_else(|| "Untitled".to_owned()),
+                link: link.clone(),
+                html: content.clone(),
+                author: name.clone(),
+                updated: to_datetime(*time),
+            },
+            Event::CratesIo {
+                name,
+                vers,
+                links,
+                yanked,
+            } => {
+                let mut html = format!(
+                    "New update: <b>{}</b> {}",
+                    html_escape::encode_text(name),
+                    html_escape::encode_text(vers)
+                );
+                if *yanked {
+                    html.push_str("<br>Yanked: true")
+                }
+                if let Some(links) = links {
+                    html.push_str(&format!("<br>Links: {}", html_escape::encode_text(links)))
+                }
+                Self {
+                    id,
+                    title: format!("{} {}", name, vers),
+                    link: archived.event.link(),
+                    html: Some(html),
+                    author: Some("Crates.io".to_owned()),
+                    updated: to_datetime(archived.time),
+                }
+            }
+            Event::GitHubRelease {
+                repo,
+                tag,
+                name,
+                body,
+                link,
+                time,
+                ..
+            } => Self {
+                id,
+                title: format!("{} {}", repo, name.as_deref().unwrap_or(tag)),
+                link: Some(link.clone()),
+                // Release notes are markdown, shown as preformatted text
+                html: body
+                    .as_ref()
+                    .map(|x| format!("<pre>{}</pre>", html_escape::encode_text(x))),
+                author: Some(repo.clone()),
+                updated: to_datetime(*time),
+            },
+            Event::Git {
+                repo,
+                tag,
+                commit,
+                author,
+                message,
+                time,
+                ..
+            } => Self {
+                id,
+                title: match tag {
+                    Some(tag) => format!("{} {}", repo, tag),
+                    None => format!("{}: {}", repo, message.lines().next().unwrap_or_default()),
+                },
+                link: None,
+                html: Some(format!(
+                    "<code>{}</code><pre>{}</pre>",
+                    commit,
+                    html_escape::encode_text(message)
+                )),
+                author: Some(author.clone()),
+                updated: to_datetime(*time),
+            },
+        }
+    }
+}
+
+fn to_datetime(ts: i64) -> DateTime<FixedOffset> {
+    DateTime::from_timestamp(ts, 0)
+        .unwrap_or_default()
+        .fixed_offset()
+}
+
+fn atom(feed: &FeedInfo, entries: &[Entry]) -> String {
+    let link = |href: &str, rel: &str| atom::Link {
+        href: href.to_owned(),
+        rel: rel.to_owned(),
+        ..Default::default()
+    };
+    atom::Feed {
+        title: atom::Text::plain(&feed.title),
+        id: feed.url.clone(),
+        updated: feed.updated,
+        links: vec![link(&feed.url, "self"), link(&feed.home, "alternate")],
+        entries: entries
+            .iter()
+            .map(|entry| atom::Entry {
+                title: atom::Text::plain(&entry.title),
+                id: entry.id.clone(),
+                updated: entry.updated,
+                links: entry.link.iter().map(|x| link(x, "alternate")).collect(),
+                authors: entry
+                    .author
+                    .iter()
+                    .map(|name| atom::Person {
+                        name: name.clone(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                content: entry.html.as_ref().map(|html| atom::Content {
+                    value: Some(html.clone()),
+                    content_type: Some("html".to_owned()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+    .to_string()
+}
+
+fn rss(feed: &FeedInfo, entries: &[Entry]) -> String {
+    rss::Channel {
+        title: feed.title.clone(),
+        link: feed.home.clone(),
+        description: feed.title.clone(),
+        last_build_date: Some(feed.updated.to_rfc2822()),
+        items: entries
+            .iter()
+            .map(|entry| rss::Item {
+                title: Some(entry.title.clone()),
+                link: entry.link.clone(),
+                description: entry.html.clone(),
+                guid: Some(rss::Guid {
+                    value: entry.id.clone(),
+                    permalink: false,
+                }),
+                pub_date: Some(entry.updated.to_rfc2822()),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    }
+    .to_string()
+}
+
+/// Feed in [JSON Feed](https://www.jsonfeed.org/version/1.1/) format
+fn json_feed(feed: &FeedInfo, entries: &[Entry]) -> String {
+    let items = entries
+        .iter()
+        .map(|entry| {
+            let mut item = json!({
+                "id": entry.id,
+                "title": entry.title,
+                "content_html": entry.html.as_deref().unwrap_or_default(),
+                "date_published": entry.updated.to_rfc3339(),
+            });
+            if let Some(link) = &entry.link {
+                item["url"] = link.as_str().into();
+            }
+            if let Some(author) = &entry.author {
+                item["authors"] = json!([{ "name": author }]);
+            }
+            item
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": feed.title,
+        "home_page_url": feed.home,
+        "feed_url": feed.url,
+        "items": items,
+    })
+    .to_string()
+}
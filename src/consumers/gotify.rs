@@ -0,0 +1,170 @@
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    by_index, fan_out, get_client, get_db, index_targets, permanent, Consumer, DeadLetters, Event,
+    EventKind, GotifyConfig, Inbox, Target, Templates,
+};
+
+/// Consumer that pushes events to [Gotify](https://gotify.net) applications
+pub struct GotifyConsumer;
+
+impl GotifyConsumer {
+    /// Layouts of messages in markdown when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"{% if content -%}
+{% set text = content | html2text | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{% endif -%}
+{% if link %}[Read more]({{ link }}){% endif %}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"New update: **{{ name }}** {{ vers }}
+{%- if yanked %}
+Yanked: true
+{%- endif %}
+{%- if links %}
+Links: {{ links }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"{% if body -%}
+{% set text = body | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{% endif -%}
+[Release notes]({{ link }})"#,
+        ),
+        (
+            EventKind::Git,
+            r#"`{{ commit | truncate(length=7, end="") }}`{% if branch %} on **{{ branch }}**{% endif %} by {{ author }}
+{%- if message %}
+{% set text = message | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for GotifyConsumer {
+    type Config = GotifyConfig;
+
+    const NAME: &'static str = "consumer_gotify";
+
+    fn run(inbox: Inbox, config: GotifyConfig) -> JoinHandle<()> {
+        run_gotify(inbox, config)
+    }
+}
+
+pub fn run_gotify(mut inbox: Inbox, config: GotifyConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(GotifyConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        let tokens = index_targets(&config.tokens);
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let msg = match templates.render(&envelope.event, &vars) {
+                Ok(x) => x,
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&tokens), e);
+                    continue;
+                }
+            };
+            let payload = message(&envelope.event, &msg, &config);
+            debug!("Payload: {}", payload);
+
+            let targets = envelope.targets.resolve(&tokens);
+            let failed = fan_out(targets, "Message pushed as application", |token| {
+                push_to(token, &payload, &config)
+            })
+            .await;
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// Build a message of `event`, with extras for clients to render markdown and
+/// open link of event on click
+fn message(event: &Event, msg: &str, config: &GotifyConfig) -> Value {
+    let title = match event {
+        Event::Feed { name, title, .. } => title
+            .as_deref()
+            .or(name.as_deref())
+            .unwrap_or("Feed")
+            .to_owned(),
+        Event::CratesIo { name, vers, .. } => format!("{} {}", name, vers),
+        Event::GitHubRelease {
+            repo, tag, name, ..
+        } => format!("{} {}", repo, name.as_deref().unwrap_or(tag)),
+        Event::Git {
+            repo, tag, message, ..
+        } => match tag {
+            Some(tag) => format!("{} {}", repo, tag),
+            None => format!("{}: {}", repo, message.lines().next().unwrap_or_default()),
+        },
+    };
+    let mut payload = json!({ "title": title, "message": msg });
+    if let Some(priority) = config.priority {
+        payload["priority"] = priority.into();
+    }
+    if config.markdown {
+        payload["extras"]["client::display"] = json!({ "contentType": "text/markdown" });
+    }
+    if let Some(link) = event.link() {
+        payload["extras"]["client::notification"] = json!({ "click": { "url": link } });
+    }
+    payload
+}
+
+/// Push `payload` as application of `token`, by index in config, with retries
+async fn push_to(token: &Target, payload: &Value, config: &GotifyConfig) -> Result<()> {
+    let token = by_index(&config.tokens, "tokens", token)?;
+    config.retry.retry(|| push(token, payload, config)).await
+}
+
+async fn push(token: &str, payload: &Value, config: &GotifyConfig) -> Result<()> {
+    let res = get_client()
+        .post(format!("{}/message", config.server.trim_end_matches('/')))
+        .header("X-Gotify-Key", token)
+        .json(payload)
+        .send()
+        .await
+        .wrap_err("Failed to request gotify")?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let text = res.text().await.unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    if status.is_client_error() {
+        Err(permanent(e))
+    } else {
+        Err(e)
+    }
+}
@@ -0,0 +1,546 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{bail, eyre, Context},
+    Report, Result,
+};
+use log::{debug, info, warn};
+use serde_json::json;
+use tokio::{
+    io::{split, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf},
+    task::JoinHandle,
+    time::{sleep_until, timeout, timeout_at, Instant},
+};
+
+use crate::{
+    check_secs, connect_stream, get_db, is_permanent, permanent, Consumer, DeadLetters, Envelope,
+    EventKind, Inbox, IrcConfig, RateLimiter, RetryConfig, Route, Stream, Target, Templates,
+};
+
+/// Max length of text of a message in bytes, leaving room for the command
+/// and prefix added by server within 512 bytes of a line
+const MAX_TEXT_LENGTH: usize = 400;
+
+/// How long the connection can be silent before server is pinged, and then
+/// before it's considered dead
+const PING_INTERVAL: Duration = Duration::from_secs(120);
+
+/// How long to wait for server to confirm joining a channel
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for NickServ to answer identifying
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Consumer that posts one line summaries of events to IRC channels, over a
+/// persistent connection
+pub struct IrcConsumer;
+
+impl IrcConsumer {
+    /// Layouts of messages when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"{% if name %}[{{ name }}] {% endif %}{{ title | default(value="Untitled") }}{% if link %} {{ link }}{% endif %}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"{{ name }} {{ vers }}{% if yanked %} (yanked){% endif %} https://crates.io/crates/{{ name }}/{{ vers }}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"[{{ repo }}] {% if name %}{{ name }}{% else %}{{ tag }}{% endif %}{% if prerelease %} (pre-release){% endif %} {{ link }}"#,
+        ),
+        (
+            EventKind::Git,
+            r#"[{{ repo }}] {% if tag %}tag {{ tag }}{% else %}{{ branch }}{% endif %} {{ commit | truncate(length=7, end="") }} {{ author }}{% if message %}: {{ message | split(pat="\n") | first }}{% endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for IrcConsumer {
+    type Config = IrcConfig;
+
+    const NAME: &'static str = "consumer_irc";
+
+    fn validate(config: &IrcConfig, _routes: &[Route]) -> Result<()> {
+        check_secs("throttle_interval", config.throttle_interval)?;
+        check_secs("reconnect_delay", config.reconnect_delay)
+    }
+
+    fn run(inbox: Inbox, config: IrcConfig) -> JoinHandle<()> {
+        run_irc(inbox, config)
+    }
+}
+
+/// Messages of an event not sent yet, kept over reconnections
+struct Pending {
+    envelope: Envelope,
+    /// Channels with text to post to them
+    messages: VecDeque<(Target, String)>,
+    /// Failed attempts to join channel of first message
+    attempt: u32,
+    /// When to try joining channel of first message again
+    retry_at: Option<Instant>,
+    /// Channels that couldn't be joined after retries
+    failed: Vec<(Target, Report)>,
+}
+
+impl Pending {
+    /// First message is posted, move on to next one
+    fn sent(&mut self) {
+        self.messages.pop_front();
+        self.attempt = 0;
+        self.retry_at = None;
+    }
+
+    /// Joining channel of first message failed with `e`, try again later or
+    /// give up on it as configured by `retry`
+    fn failed(&mut self, e: Report, retry: &RetryConfig) {
+        if self.attempt >= retry.max_retries || is_permanent(&e) {
+            if let Some((channel, _)) = self.messages.pop_front() {
+                self.failed.push((channel, e))
+            }
+            self.attempt = 0;
+            self.retry_at = None;
+            return;
+        }
+        let delay = retry.delay(self.attempt);
+        warn!(
+            "{:#} (Retrying in {:.1}s, {} retries left)",
+            e,
+            delay.as_secs_f64(),
+            retry.max_retries - self.attempt
+        );
+        self.attempt += 1;
+        self.retry_at = Some(Instant::now() + delay);
+    }
+}
+
+pub fn run_irc(mut inbox: Inbox, config: IrcConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(IrcConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let channels = config
+            .channels
+            .iter()
+            .cloned()
+            .map(Target::Name)
+            .collect::<Vec<_>>();
+        let limiter = RateLimiter::new(
+            Duration::from_secs_f64(config.throttle_interval),
+            config.throttle_burst,
+        );
+        let mut pending = None;
+
+        loop {
+            let res = session(
+                &mut inbox,
+                &mut pending,
+                &dead_letters,
+                &templates,
+                &channels,
+                &limiter,
+                &config,
+            )
+            .await;
+            match res {
+                Ok(()) => return,
+                Err(e) => warn!("Disconnected from IRC server: {:#}", e),
+            }
+            tokio::time::sleep(Duration::from_secs_f64(config.reconnect_delay)).await;
+            info!("Reconnecting to IRC server")
+        }
+    })
+}
+
+/// Connect to server and post events until `inbox` is closed
+async fn session(
+    inbox: &mut Inbox,
+    pending: &mut Option<Pending>,
+    dead_letters: &DeadLetters,
+    templates: &Templates,
+    channels: &[Target],
+    limiter: &RateLimiter,
+    config: &IrcConfig,
+) -> Result<()> {
+    let mut conn = Connection::connect(config).await?;
+    conn.register(config).await?;
+    for channel in config.channels.iter() {
+        // Channels not joined now are joined again when posting to them
+        if let Err(e) = conn.join(channel).await? {
+            warn!("{:#}", e)
+        }
+    }
+
+    loop {
+        let (channel, text) = match pending {
+            Some(x) if x.messages.is_empty() => {
+                let Pending {
+                    envelope, failed, ..
+                } = pending.take().unwrap();
+                if failed.is_empty() {
+                    info!("Event #{} posted to IRC", envelope.id);
+                }
+                let failed = failed.iter().map(|(channel, e)| (channel, e)).collect();
+                envelope.settle(dead_letters, failed);
+                continue;
+            }
+            Some(Pending { messages, .. }) => messages.front().cloned().unwrap(),
+            None => {
+                tokio::select! {
+                    line = conn.next_line() => conn.handle(&Message::parse(&line?)).await?,
+                    envelope = inbox.recv() => match envelope {
+                        Some(envelope) => {
+                            *pending = prepare(envelope, dead_letters, templates, channels)
+                        }
+                        None => {
+                            conn.send("QUIT :Bye").await.ok();
+                            return Ok(());
+                        }
+                    }
+                }
+                continue;
+            }
+        };
+
+        // Keep answering server while waiting for retry and turn
+        let retry_at = pending.as_ref().and_then(|x| x.retry_at);
+        let turn = async {
+            if let Some(retry_at) = retry_at {
+                sleep_until(retry_at).await
+            }
+            limiter.wait().await
+        };
+        tokio::pin!(turn);
+        loop {
+            tokio::select! {
+                _ = &mut turn => break,
+                line = conn.next_line() => conn.handle(&Message::parse(&line?)).await?,
+            }
+        }
+
+        let name = channel.to_string();
+        if !conn.joined.contains(&name) {
+            if let Err(e) = conn.join(&name).await? {
+                if let Some(pending) = pending {
+                    pending.failed(e, &config.retry)
+                }
+                continue;
+            }
+        }
+        conn.send(&format!("PRIVMSG {} :{}", name, text)).await?;
+        if let Some(pending) = pending {
+            pending.sent()
+        }
+    }
+}
+
+/// Render `envelope` into a message to each of its targets
+fn prepare(
+    envelope: Envelope,
+    dead_letters: &DeadLetters,
+    templates: &Templates,
+    channels: &[Target],
+) -> Option<Pending> {
+    info!("New event: {}", envelope.event);
+    let text = match templates.render(&envelope.event, json!({})) {
+        Ok(x) => one_line(&x),
+        Err(e) => {
+            envelope.fail(dead_letters, envelope.targets.resolve(channels), e);
+            return None;
+        }
+    };
+    let messages = envelope
+        .targets
+        .resolve(channels)
+        .iter()
+        .map(|channel| (channel.clone(), text.clone()))
+        .collect();
+    Some(Pending {
+        envelope,
+        messages,
+        attempt: 0,
+        retry_at: None,
+        failed: vec![],
+    })
+}
+
+/// Join lines of `text` with spaces, and cut it to fit in a message
+pub fn one_line(text: &str) -> String {
+    let text = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(|c: char| c.is_control(), "");
+    if text.len() <= MAX_TEXT_LENGTH {
+        return text;
+    }
+
+    let mut end = MAX_TEXT_LENGTH - '…'.len_utf8();
+    while !text.is_char_boundary(end) {
+        end -= 1
+    }
+    format!("{}…", &text[..end])
+}
+
+/// A message from server
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Message {
+    /// Nick or server the message is from
+    source: Option<String>,
+    command: String,
+    params: Vec<String>,
+}
+
+impl Message {
+    fn parse(line: &str) -> Self {
+        let (source, line) = match line.strip_prefix(':') {
+            Some(rest) => {
+                let (source, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let nick = source.split('!').next().unwrap_or(source);
+                (Some(nick.to_owned()), rest)
+            }
+            None => (None, line),
+        };
+        let (line, trailing) = match line.split_once(" :") {
+            Some((line, trailing)) => (line, Some(trailing)),
+            None => (line, None),
+        };
+        let mut words = line.split(' ').filter(|x| !x.is_empty()).map(str::to_owned);
+        let command = words.next().unwrap_or_default().to_uppercase();
+        let mut params = words.collect::<Vec<_>>();
+        params.extend(trailing.map(str::to_owned));
+        Self {
+            source,
+            command,
+            params,
+        }
+    }
+
+    fn param(&self, n: usize) -> &str {
+        self.params.get(n).map_or("", String::as_str)
+    }
+}
+
+struct Connection {
+    reader: Lines<BufReader<ReadHalf<Box<dyn Stream>>>>,
+    writer: WriteHalf<Box<dyn Stream>>,
+    nick: String,
+    joined: HashSet<String>,
+    /// Whether server is pinged for silence and not answered yet
+    pinged: bool,
+}
+
+impl Connection {
+    async fn connect(config: &IrcConfig) -> Result<Self> {
+        let port = config.port.unwrap_or(if config.tls { 6697 } else { 6667 });
+        let stream = connect_stream(&config.server, port, config.tls).await?;
+        info!("Connected to {}:{}", config.server, port);
+
+        let (reader, writer) = split(stream);
+        Ok(Self {
+            reader: BufReader::new(reader).lines(),
+            writer,
+            nick: config.nickname.clone(),
+            joined: HashSet::new(),
+            pinged: false,
+        })
+    }
+
+    async fn send(&mut self, line: &str) -> Result<()> {
+        debug!("IRC send: {}", line);
+        self.writer
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await
+            .wrap_err("Failed to send to IRC server")
+    }
+
+    /// Next line from server, pinging server when it's silent for too long
+    async fn next_line(&mut self) -> Result<String> {
+        loop {
+            match timeout(PING_INTERVAL, self.reader.next_line()).await {
+                Ok(Ok(Some(line))) => {
+                    debug!("IRC recv: {}", line);
+                    self.pinged = false;
+                    return Ok(line);
+                }
+                Ok(Ok(None)) => bail!("Connection closed by server"),
+                Ok(Err(e)) => return Err(e).wrap_err("Failed to read from IRC server"),
+                Err(_) if self.pinged => bail!("Ping timeout"),
+                Err(_) => {
+                    self.pinged = true;
+                    self.send("PING :caster").await?
+                }
+            }
+        }
+    }
+
+    /// Log in as configured, returning once server welcomes us
+    async fn register(&mut self, config: &IrcConfig) -> Result<()> {
+        if config.sasl.is_some() {
+            self.send("CAP REQ :sasl").await?
+        }
+        if let Some(password) = &config.password {
+            self.send(&format!("PASS {}", password)).await?
+        }
+        self.send(&format!("NICK {}", self.nick)).await?;
+        self.send(&format!(
+            "USER {} 0 * :{}",
+            config.username.as_deref().unwrap_or(&config.nickname),
+            config.realname.as_deref().unwrap_or(&config.nickname)
+        ))
+        .await?;
+
+        let mut logged_in = false;
+        loop {
+            let msg = Message::parse(&self.next_line().await?);
+            match (msg.command.as_str(), msg.param(1)) {
+                ("001", _) => break,
+                ("CAP", "ACK") => self.send("AUTHENTICATE PLAIN").await?,
+                ("CAP", "NAK") => {
+                    warn!("SASL is not supported by IRC server");
+                    self.send("CAP END").await?
+                }
+                ("AUTHENTICATE", _) if msg.param(0) == "+" => {
+                    let sasl = config
+                        .sasl
+                        .as_ref()
+                        .ok_or_else(|| eyre!("SASL is not configured"))?;
+                    let token = base64::encode(format!(
+                        "{}\0{}\0{}",
+                        sasl.username, sasl.username, sasl.password
+                    ));
+                    self.send(&format!("AUTHENTICATE {}", token)).await?
+                }
+                ("903", _) => {
+                    info!("Logged in to IRC server with SASL");
+                    logged_in = true;
+                    self.send("CAP END").await?
+                }
+                ("902" | "904" | "905" | "906" | "908", _) => {
+                    bail!("SASL authentication failed: {}", msg.params.join(" "))
+                }
+                // Nickname in use
+                ("432" | "433", _) => {
+                    self.nick.push('_');
+                    warn!("Nickname is unavailable, trying {}", self.nick);
+                    self.send(&format!("NICK {}", self.nick)).await?
+                }
+                _ => self.handle(&msg).await?,
+            }
+        }
+        info!("Registered to IRC server as {}", self.nick);
+
+        match &config.nickserv_password {
+            Some(_) if logged_in => debug!("Logged in with SASL, not identifying to NickServ"),
+            Some(password) => self.identify(password).await?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Identify to NickServ with `password`, and wait for it to answer so that
+    /// channels requiring it can be joined
+    async fn identify(&mut self, password: &str) -> Result<()> {
+        self.send(&format!("PRIVMSG NickServ :IDENTIFY {}", password))
+            .await?;
+        let deadline = Instant::now() + IDENTIFY_TIMEOUT;
+        loop {
+            let msg = match timeout_at(deadline, self.next_line()).await {
+                Ok(line) => Message::parse(&line?),
+                Err(_) => {
+                    warn!("NickServ didn't answer identifying");
+                    return Ok(());
+                }
+            };
+            match msg.command.as_str() {
+                // Logged in
+                "900" => {
+                    info!("Identified to NickServ");
+                    return Ok(());
+                }
+                "NOTICE" if msg.source.as_deref() == Some("NickServ") => {
+                    info!("NickServ: {}", msg.param(1));
+                    return Ok(());
+                }
+                _ => self.handle(&msg).await?,
+            }
+        }
+    }
+
+    /// Join `channel` and wait for server to confirm it. Refusals of server
+    /// are returned as inner errors, leaving the connection usable.
+    async fn join(&mut self, channel: &str) -> Result<Result<()>> {
+        self.send(&format!("JOIN {}", channel)).await?;
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        loop {
+            let msg = match timeout_at(deadline, self.next_line()).await {
+                Ok(line) => Message::parse(&line?),
+                Err(_) => return Ok(Err(eyre!("Server didn't confirm joining {}", channel))),
+            };
+            let (target, is_self) = match msg.command.as_str() {
+                "JOIN" => (msg.param(0), msg.source.as_deref() == Some(&self.nick)),
+                _ => (msg.param(1), true),
+            };
+            if !is_self || !target.eq_ignore_ascii_case(channel) {
+                self.handle(&msg).await?;
+                continue;
+            }
+            match msg.command.as_str() {
+                // Our own JOIN, topic, or end of names
+                "JOIN" | "332" | "366" => {
+                    info!("Joined {}", channel);
+                    self.joined.insert(channel.to_owned());
+                    return Ok(Ok(()));
+                }
+                // No such channel
+                "403" => {
+                    let e = eyre!("Failed to join {}: {}", channel, msg.param(2));
+                    return Ok(Err(permanent(e)));
+                }
+                // Cannot join channel
+                "405" | "471" | "473" | "474" | "475" | "477" => {
+                    return Ok(Err(eyre!("Failed to join {}: {}", channel, msg.param(2))))
+                }
+                _ => self.handle(&msg).await?,
+            }
+        }
+    }
+
+    /// Handle a line from server besides registration
+    async fn handle(&mut self, msg: &Message) -> Result<()> {
+        match msg.command.as_str() {
+            "PING" => self.send(&format!("PONG :{}", msg.param(0))).await?,
+            "ERROR" => bail!("Closed by server: {}", msg.param(0)),
+            "KICK" if msg.param(1) == self.nick => {
+                warn!("Kicked from {}: {}", msg.param(0), msg.param(2));
+                self.joined.remove(msg.param(0));
+            }
+            // Cannot join channel
+            "403" | "405" | "471" | "473" | "474" | "475" | "477" => {
+                warn!("Failed to join {}: {}", msg.param(1), msg.param(2));
+                self.joined.remove(msg.param(1));
+            }
+            "NICK" if msg.source.as_deref() == Some(&self.nick) => {
+                self.nick = msg.param(0).to_owned()
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
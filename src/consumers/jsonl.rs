@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use color_eyre::{eyre::Context, Result};
+use log::{debug, info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{get_db, Consumer, DeadLetters, EventRecord, Inbox, JsonlConfig, Target};
+
+/// Consumer that writes events as [JSON Lines](https://jsonlines.org/) to
+/// stdout or files, in schema of [`EventRecord`]
+pub struct JsonlConsumer;
+
+impl Consumer for JsonlConsumer {
+    type Config = JsonlConfig;
+
+    const NAME: &'static str = "consumer_jsonl";
+
+    fn run(inbox: Inbox, config: JsonlConfig) -> JoinHandle<()> {
+        run_jsonl(inbox, config)
+    }
+}
+
+pub fn run_jsonl(mut inbox: Inbox, config: JsonlConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let paths = [Target::Name(config.path.clone())];
+        let mut sinks = HashMap::new();
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let mut line = match serde_json::to_vec(&EventRecord(envelope.event.clone())) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Failed to serialize event: {}", e);
+                    continue;
+                }
+            };
+            line.push(b'\n');
+
+            let mut failed = vec![];
+            for target in envelope.targets.resolve(&paths) {
+                let path = target.to_string();
+                let sink = sinks.entry(path.clone()).or_insert_with(|| Sink::new(path));
+                match sink.write(&line, &config) {
+                    Ok(()) => debug!("Event #{} written to {}", envelope.id, target),
+                    Err(e) => failed.push((target, e)),
+                }
+            }
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// A file or stdout that lines are appended to
+struct Sink {
+    path: String,
+    file: Option<File>,
+    size: u64,
+}
+
+impl Sink {
+    fn new(path: String) -> Self {
+        Self {
+            path,
+            file: None,
+            size: 0,
+        }
+    }
+
+    fn write(&mut self, line: &[u8], config: &JsonlConfig) -> Result<()> {
+        if self.path == "-" {
+            let mut stdout = io::stdout().lock();
+            stdout
+                .write_all(line)
+                .wrap_err("Failed to write to stdout")?;
+            return stdout.flush().wrap_err("Failed to write to stdout");
+        }
+
+        // Rotate before the line would exceed max size, unless it's the first
+        // line of file
+        let size = match &self.file {
+            Some(_) => self.size,
+            None => fs::metadata(&self.path)
+                .map(|x| x.len())
+                .unwrap_or_default(),
+        };
+        if config
+            .max_size
+            .is_some_and(|max| size > 0 && size + line.len() as u64 > max)
+        {
+            self.rotate(config.max_files)
+                .wrap_err_with(|| format!("Failed to rotate {}", self.path))?
+        }
+
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+                    .wrap_err_with(|| format!("Failed to open {}", self.path))?;
+                self.size = file.metadata().map(|x| x.len()).unwrap_or_default();
+                self.file.insert(file)
+            }
+        };
+        file.write_all(line)
+            .and_then(|_| file.flush())
+            .wrap_err_with(|| format!("Failed to write to {}", self.path))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move `<path>` to `<path>.1`, `<path>.1` to `<path>.2` and so on,
+    /// dropping the ones beyond `max_files`
+    fn rotate(&mut self, max_files: usize) -> io::Result<()> {
+        self.file = None;
+        self.size = 0;
+        if max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+
+        for n in (1..max_files).rev() {
+            let from = format!("{}.{}", self.path, n);
+            if Path::new(&from).exists() {
+                fs::rename(from, format!("{}.{}", self.path, n + 1))?
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        info!("Rotated {}", self.path);
+        Ok(())
+    }
+}
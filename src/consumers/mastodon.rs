@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    get_client, get_db, permanent, retry_after, truncate_chars, Consumer, DeadLetters, EventKind,
+    Inbox, MastodonConfig, Target, Templates,
+};
+
+/// How many characters a url counts as, regardless of its length
+const URL_LENGTH: usize = 23;
+
+static URL: Lazy<Regex> = Lazy::new(|| Regex::new(r"https?://\S+").unwrap());
+
+/// Consumer that posts events as statuses of a Mastodon account
+pub struct MastodonConsumer;
+
+impl MastodonConsumer {
+    /// Layouts of statuses when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"{% if title %}{{ title }}
+
+{% endif -%}
+{% if content -%}
+{% set text = content | html2text | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+
+{% endif -%}
+{{ link | default(value="") }}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"New update: {{ name }} {{ vers }}{% if yanked %} (yanked){% endif %}
+
+https://crates.io/crates/{{ name }}/{{ vers }}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"New {% if prerelease %}pre-release{% else %}release{% endif %} of {{ repo }}: {% if name %}{{ name }}{% else %}{{ tag }}{% endif %}
+
+{{ link }}"#,
+        ),
+        (
+            EventKind::Git,
+            r#"{% if tag %}New tag of {{ repo }}: {{ tag }}{% else %}New commit to {{ repo }} ({{ branch }}) by {{ author }}{% endif %}
+{%- if message %}
+
+{{ message | trim }}
+{%- endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for MastodonConsumer {
+    type Config = MastodonConfig;
+
+    const NAME: &'static str = "consumer_mastodon";
+
+    fn run(inbox: Inbox, config: MastodonConfig) -> JoinHandle<()> {
+        run_mastodon(inbox, config)
+    }
+}
+
+pub fn run_mastodon(mut inbox: Inbox, config: MastodonConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(MastodonConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        // Content warning counts towards the limit as well
+        let max_characters = config.max_characters.saturating_sub(
+            config
+                .content_warning
+                .as_deref()
+                .map_or(0, |x| x.chars().count()),
+        );
+        let account = Target::Name(config.instance.clone());
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let status = match templates.render(&envelope.event, &vars) {
+                Ok(x) => fit_status(x.trim(), max_characters),
+                Err(e) => {
+                    envelope.fail(&dead_letters, std::slice::from_ref(&account), e);
+                    continue;
+                }
+            };
+            let mut payload = json!({ "status": status, "visibility": config.visibility });
+            if let Some(content_warning) = &config.content_warning {
+                payload["spoiler_text"] = content_warning.as_str().into();
+            }
+            if let Some(language) = &config.language {
+                payload["language"] = language.as_str().into();
+            }
+            debug!("Payload: {}", payload);
+
+            // Same key for retries, so that a status is not posted twice
+            let key = format!("caster-{}", envelope.id);
+            let res = config.retry.retry(|| post(&payload, &key, &config)).await;
+            let failed = match res {
+                Ok(()) => {
+                    info!("Event #{} posted to mastodon", envelope.id);
+                    vec![]
+                }
+                Err(e) => vec![(&account, e)],
+            };
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// Length of `status` as counted by Mastodon, where each url counts as 23
+/// characters
+pub fn status_length(status: &str) -> usize {
+    URL.split(status).map(|x| x.chars().count()).sum::<usize>()
+        + URL.find_iter(status).count() * URL_LENGTH
+}
+
+/// Cut `status` to at most `max` characters as counted by Mastodon, keeping
+/// the trailing url if any
+pub fn fit_status(status: &str, max: usize) -> String {
+    if status_length(status) <= max {
+        return status.to_owned();
+    }
+    match status.rsplit_once(char::is_whitespace) {
+        Some((head, url)) if URL.is_match(url) && max > URL_LENGTH + 1 => {
+            // Urls left in `head` are counted in full, which only cuts more
+            format!(
+                "{}\n{}",
+                truncate_chars(head.trim_end(), max - URL_LENGTH - 1),
+                url
+            )
+        }
+        _ => truncate_chars(status, max),
+    }
+}
+
+async fn post(payload: &Value, key: &str, config: &MastodonConfig) -> Result<()> {
+    let res = get_client()
+        .post(format!(
+            "{}/api/v1/statuses",
+            config.instance.trim_end_matches('/')
+        ))
+        .bearer_auth(&config.access_token)
+        .header("Idempotency-Key", key)
+        .json(payload)
+        .send()
+        .await
+        .wrap_err("Failed to request mastodon")?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    // Time when limit is reset, e.g. `2022-01-01T00:00:00.000Z`
+    let reset = res
+        .headers()
+        .get("x-ratelimit-reset")
+        .and_then(|x| chrono::DateTime::parse_from_rfc3339(x.to_str().ok()?).ok())
+        .and_then(|x| {
+            (x.timestamp() - chrono::Utc::now().timestamp())
+                .try_into()
+                .ok()
+        })
+        .map(Duration::from_secs);
+    let text = res.text().await.unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(retry_after(e, reset.unwrap_or(Duration::from_secs(60))))
+    } else if status.is_client_error() {
+        Err(permanent(e))
+    } else {
+        Err(e)
+    }
+}
@@ -0,0 +1,183 @@
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    fan_out, get_client, get_db, permanent, retry_after, Consumer, DeadLetters, Inbox,
+    MatrixConfig, Target, TelegramConsumer, Templates,
+};
+
+/// Characters escaped in path segments, all but unreserved ones
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Consumer that sends events to matrix rooms via client-server API
+pub struct MatrixConsumer;
+
+impl Consumer for MatrixConsumer {
+    type Config = MatrixConfig;
+
+    const NAME: &'static str = "consumer_matrix";
+
+    fn run(inbox: Inbox, config: MatrixConfig) -> JoinHandle<()> {
+        run_matrix(inbox, config)
+    }
+}
+
+pub fn run_matrix(mut inbox: Inbox, config: MatrixConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        match request(&config, Method::GET, &["account", "whoami"], None).await {
+            Ok(me) => info!(
+                "Logged in as {}",
+                me["user_id"].as_str().unwrap_or_default()
+            ),
+            Err(e) => {
+                warn!("Failed to authenticate: {:#}", e);
+                return;
+            }
+        }
+
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        // Same HTML as telegram, which matrix clients also understand
+        let templates = match Templates::new(TelegramConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        let rooms = config
+            .rooms
+            .iter()
+            .cloned()
+            .map(Target::Name)
+            .collect::<Vec<_>>();
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let html = match templates.render(&envelope.event, &vars) {
+                Ok(x) => x,
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&rooms), e);
+                    continue;
+                }
+            };
+            let content = message(&config.msgtype, &html);
+            debug!("Content: {}", content);
+
+            let targets = envelope.targets.resolve(&rooms);
+            // Same transaction ID for every attempt, so the homeserver drops
+            // duplicates of requests that did succeed
+            let txn_id = format!("caster-{}", envelope.id);
+            let failed = fan_out(targets, "Message sent to room", |room| {
+                send_to(room, &content, &txn_id, &config)
+            })
+            .await;
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// Content of `m.room.message` with `html` as formatted body
+fn message(msgtype: &str, html: &str) -> Value {
+    static TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+    let body = html_escape::decode_html_entities(&TAG.replace_all(html, "")).into_owned();
+    json!({
+        "msgtype": msgtype,
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": html.replace('\n', "<br>"),
+    })
+}
+
+/// Send `content` to `room` with retries
+async fn send_to(
+    room: &Target,
+    content: &Value,
+    txn_id: &str,
+    config: &MatrixConfig,
+) -> Result<()> {
+    let room = match room {
+        Target::Name(room) => room,
+        Target::Id(_) => return Err(eyre!("Matrix rooms are targeted by ID like `!abc:host`")),
+    };
+    let path = ["rooms", room, "send", "m.room.message", txn_id];
+    let res = config
+        .retry
+        .retry(|| request(config, Method::PUT, &path, Some(content)))
+        .await?;
+    debug!(
+        "Event ID of message in room {}: {}",
+        room,
+        res["event_id"].as_str().unwrap_or_default()
+    );
+    Ok(())
+}
+
+/// Request `/_matrix/client/v3/{path}` of homeserver
+async fn request(
+    config: &MatrixConfig,
+    method: Method,
+    path: &[&str],
+    body: Option<&Value>,
+) -> Result<Value> {
+    let path = path
+        .iter()
+        .map(|x| utf8_percent_encode(x, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+    let url = format!(
+        "{}/_matrix/client/v3/{}",
+        config.homeserver.trim_end_matches('/'),
+        path
+    );
+    debug!("New request to {}", url);
+
+    let mut req = get_client()
+        .request(method, url)
+        .bearer_auth(&config.access_token);
+    if let Some(body) = body {
+        req = req.json(body)
+    }
+    let res = req.send().await.wrap_err("Failed to request homeserver")?;
+    let status = res.status();
+    let text = res.text().await.wrap_err("Failed to read response")?;
+    if status.is_success() {
+        return serde_json::from_str(&text).wrap_err("Failed to parse response");
+    }
+
+    let res = serde_json::from_str::<Value>(&text).unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let delay = res["retry_after_ms"].as_u64().unwrap_or(1000);
+        Err(retry_after(e, Duration::from_millis(delay)))
+    } else if status.is_client_error() {
+        Err(permanent(e))
+    } else {
+        Err(e)
+    }
+}
@@ -1,23 +1,169 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{fmt::Display, future::Future, sync::Arc, time::SystemTime};
 
-use color_eyre::Result;
-use futures::future::join_all;
-use log::error;
-use tokio::task::JoinHandle;
+use color_eyre::{eyre::eyre, Report, Result};
+use futures::{future::join_all, stream::FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use tokio::{sync::mpsc, task::JoinHandle};
 
-use crate::{Config, TX};
+use crate::{
+    Config, DeadLetters, Event, Outbox, OutboxStore, Queued, Registry, Route, Target, Targets,
+};
 
-mod telegram;
+mod_use::mod_use![
+    telegram,
+    discord,
+    slack,
+    matrix,
+    email,
+    webhook,
+    jsonl,
+    feed_server,
+    ntfy,
+    gotify,
+    irc,
+    mastodon,
+    mqtt
+];
 
-pub async fn run_consumer(tx: TX, config: Arc<Config>) -> Result<()> {
-    let start = SystemTime::now();
+/// An [`Event`] routed to a consumer. Consumers should [`ack`](Self::ack) it
+/// once it's delivered, or it will be delivered again on restart.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub id: u64,
+    pub event: Event,
+    pub targets: Targets,
+    consumer: &'static str,
+    store: OutboxStore,
+}
+
+impl Envelope {
+    pub fn new(
+        queued: Queued,
+        targets: Targets,
+        consumer: &'static str,
+        store: OutboxStore,
+    ) -> Self {
+        Self {
+            id: queued.id,
+            event: queued.event,
+            targets,
+            consumer,
+            store,
+        }
+    }
+
+    /// Acknowledge that the event is delivered
+    pub fn ack(&self) -> Result<()> {
+        self.store.ack(self.consumer, self.id)
+    }
+
+    /// Save targets that still `failed` after retries as dead letters, and
+    /// acknowledge the event if all went well. Otherwise it's delivered again
+    /// on restart.
+    pub fn settle<T: Display>(&self, dead_letters: &DeadLetters, failed: Vec<(&Target, T)>) {
+        let mut done = true;
+        for (target, e) in failed {
+            match dead_letters.push(
+                self.consumer,
+                self.event.clone(),
+                vec![target.clone()],
+                format!("{:#}", e),
+            ) {
+                Ok(id) => warn!(
+                    "Failed to deliver to {}, saved as dead letter #{}: {:#}",
+                    target, id, e
+                ),
+                Err(e) => {
+                    warn!("Failed to save dead letter: {}", e);
+                    done = false
+                }
+            }
+        }
+
+        if done {
+            if let Err(e) = self.ack() {
+                warn!("Failed to acknowledge event #{}: {}", self.id, e)
+            }
+        }
+    }
+
+    /// Save the event as a dead letter for all `targets` with error `e`, e.g.
+    /// when it can't be rendered
+    pub fn fail<T: Display>(&self, dead_letters: &DeadLetters, targets: &[Target], e: T) {
+        self.settle(dead_letters, targets.iter().map(|x| (x, &e)).collect())
+    }
+}
+
+/// Events routed to a consumer
+pub type Inbox = mpsc::Receiver<Envelope>;
 
-    let mut handles: Vec<JoinHandle<()>> = vec![];
+/// Deliver to all `targets` at once with `deliver`, logging `done` along with
+/// each target delivered to. Returns targets that failed.
+pub async fn fan_out<'a, F, Fut>(
+    targets: &'a [Target],
+    done: &str,
+    deliver: F,
+) -> Vec<(&'a Target, Report)>
+where
+    F: Fn(&'a Target) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let deliver = &deliver;
+    let mut stream = targets
+        .iter()
+        .map(|target| async move { (target, deliver(target).await) })
+        .collect::<FuturesUnordered<_>>();
 
-    if let Some(ref config) = config.consumer_telegram {
-        handles.push(telegram::run_telegram(tx.subscribe(), config.clone()));
+    let mut failed = vec![];
+    while let Some((target, res)) = stream.next().await {
+        match res {
+            Ok(()) => info!("{} {}", done, target),
+            Err(e) => failed.push((target, e)),
+        }
+    }
+    failed
+}
+
+/// Targets of all `secrets` in config, e.g. urls of webhooks. By index,
+/// keeping secret urls and tokens out of logs and dead letters.
+pub fn index_targets<T>(secrets: &[T]) -> Vec<Target> {
+    (0..secrets.len() as i64).map(Target::Id).collect()
+}
+
+/// Entry of `secrets` in config option `name` that `target` refers to by index
+pub fn by_index<'a, T>(secrets: &'a [T], name: &str, target: &Target) -> Result<&'a T> {
+    match target {
+        Target::Id(i) => usize::try_from(*i).ok().and_then(|i| secrets.get(i)),
+        Target::Name(_) => None,
+    }
+    .ok_or_else(|| eyre!("Targets should be indexes in `{}`", name))
+}
+
+/// A sink of [`Event`]s. Register implementations with
+/// [`Registry::consumer`].
+pub trait Consumer: 'static {
+    /// Name of config section, e.g. `consumer_telegram`
+    const NAME: &'static str;
+
+    type Config: DeserializeOwned + Send + 'static;
+
+    /// Check `config`, along with `routes` that may target the consumer, so
+    /// that mistakes are reported on startup
+    fn validate(_config: &Self::Config, _routes: &[Route]) -> Result<()> {
+        Ok(())
     }
 
+    /// Start the consumer, which should handle events received from `inbox`
+    fn run(inbox: Inbox, config: Self::Config) -> JoinHandle<()>;
+}
+
+pub async fn run_consumer(outbox: Outbox, config: Arc<Config>, registry: &Registry) -> Result<()> {
+    let start = SystemTime::now();
+
+    let handles = registry.spawn_consumers(&outbox, &config)?;
+    drop(outbox);
+
     join_all(handles).await.into_iter().for_each(|res| {
         if let Err(e) = res {
             error!("{}", e)
@@ -25,7 +171,7 @@ pub async fn run_consumer(tx: TX, config: Arc<Config>) -> Result<()> {
     });
 
     if start.elapsed()?.as_millis() < 500 {
-        log::warn!("Consumers shutting down too quickly, did you config both consumers right?")
+        log::warn!("Consumers shutting down too quickly, did you config consumers right?")
     }
     Ok(())
 }
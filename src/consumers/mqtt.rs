@@ -0,0 +1,372 @@
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{bail, Context},
+    Report, Result,
+};
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    check_secs, connect_stream, get_db, get_hash, Consumer, DeadLetters, Envelope, Event,
+    EventRecord, Inbox, MqttConfig, Route, Stream, Target,
+};
+
+/// How long to wait for a response of broker
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Types of control packets, in high 4 bits of first byte
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Consumer that publishes events as JSON to an MQTT broker, speaking MQTT
+/// 3.1.1
+pub struct MqttConsumer;
+
+impl Consumer for MqttConsumer {
+    type Config = MqttConfig;
+
+    const NAME: &'static str = "consumer_mqtt";
+
+    fn validate(config: &MqttConfig, routes: &[Route]) -> Result<()> {
+        if config.qos > 2 {
+            bail!("Invalid QoS {}, should be 0, 1 or 2", config.qos)
+        }
+        if config.password.is_some() && config.username.is_none() {
+            bail!("`password` is set without `username`")
+        }
+        check_secs("reconnect_delay", config.reconnect_delay)?;
+        // Strings of CONNECT are prefixed with their length as 2 bytes
+        let strings = [
+            ("client_id", Some(&config.client_id)),
+            ("username", config.username.as_ref()),
+            ("password", config.password.as_ref()),
+        ];
+        for (name, value) in strings {
+            if value.is_some_and(|x| x.len() > u16::MAX as usize) {
+                bail!("`{}` is longer than {} bytes", name, u16::MAX)
+            }
+        }
+        // Or topics would start with `/`
+        if config.topic_prefix.is_empty() {
+            bail!("`topic_prefix` is empty")
+        }
+        check_topic(&config.topic_prefix)?;
+        // Other routes may target channels of other consumers, which are only
+        // checked once routed here
+        for route in routes
+            .iter()
+            .filter(|x| x.consumers.iter().any(|x| x == Self::NAME))
+        {
+            for target in route.targets.iter() {
+                check_target(target)?
+            }
+        }
+        Ok(())
+    }
+
+    fn run(inbox: Inbox, config: MqttConfig) -> JoinHandle<()> {
+        run_mqtt(inbox, config)
+    }
+}
+
+pub fn run_mqtt(mut inbox: Inbox, config: MqttConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Err(e) = MqttConsumer::validate(&config, &[]) {
+            warn!("{:#}", e);
+            return;
+        }
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        // Event being published, kept over reconnections
+        let mut pending = None;
+
+        loop {
+            match session(&mut inbox, &mut pending, &dead_letters, &config).await {
+                Ok(()) => return,
+                Err(e) => warn!("Disconnected from MQTT broker: {:#}", e),
+            }
+            tokio::time::sleep(Duration::from_secs_f64(config.reconnect_delay)).await;
+            info!("Reconnecting to MQTT broker")
+        }
+    })
+}
+
+/// Connect to broker and publish events until `inbox` is closed
+async fn session(
+    inbox: &mut Inbox,
+    pending: &mut Option<Envelope>,
+    dead_letters: &DeadLetters,
+    config: &MqttConfig,
+) -> Result<()> {
+    let mut conn = Connection::connect(config).await?;
+    let keep_alive = Duration::from_secs(config.keep_alive.into());
+
+    loop {
+        let envelope = match pending {
+            Some(envelope) => envelope,
+            None => {
+                tokio::select! {
+                    envelope = inbox.recv() => match envelope {
+                        Some(envelope) => {
+                            info!("New event: {}", envelope.event);
+                            pending.insert(envelope)
+                        }
+                        None => {
+                            conn.send(DISCONNECT << 4, &[]).await.ok();
+                            return Ok(());
+                        }
+                    },
+                    _ = tokio::time::sleep(keep_alive), if !keep_alive.is_zero() => {
+                        conn.ping().await?;
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let payload = serde_json::to_vec(&EventRecord(envelope.event.clone()))
+            .wrap_err("Failed to serialize event")?;
+        let (topics, failed) = topics(envelope, config);
+        for topic in topics {
+            conn.publish(&topic, &payload, config).await?;
+            debug!("Event #{} published to {}", envelope.id, topic)
+        }
+        envelope.settle(dead_letters, failed);
+        *pending = None;
+    }
+}
+
+/// Topics targeted by routes, or the topic derived from event. Targets that
+/// can't be published to are returned apart, as they never will.
+fn topics<'a>(
+    envelope: &'a Envelope,
+    config: &MqttConfig,
+) -> (Vec<String>, Vec<(&'a Target, Report)>) {
+    let targets = envelope.targets.resolve(&[]);
+    if !targets.is_empty() {
+        let mut topics = vec![];
+        let mut failed = vec![];
+        for target in targets {
+            match check_target(target) {
+                Ok(()) => topics.push(target.to_string()),
+                Err(e) => failed.push((target, e)),
+            }
+        }
+        return (topics, failed);
+    }
+
+    let topic = match &envelope.event {
+        Event::Feed { feed, name, .. } => format!(
+            "{}/feed/{}",
+            config.topic_prefix,
+            name.as_deref().map_or_else(|| get_hash(feed), topic_level)
+        ),
+        Event::CratesIo { name, .. } => format!("{}/crates/{}", config.topic_prefix, name),
+        // Owner and name of repository as two levels
+        Event::GitHubRelease { repo, .. } => format!(
+            "{}/github/{}",
+            config.topic_prefix,
+            repo.split('/')
+                .map(topic_level)
+                .collect::<Vec<_>>()
+                .join("/")
+        ),
+        Event::Git { repo, .. } => format!("{}/git/{}", config.topic_prefix, topic_level(repo)),
+    };
+    (vec![topic], vec![])
+}
+
+/// Check that `target` is a topic that can be published to
+fn check_target(target: &Target) -> Result<()> {
+    match target {
+        Target::Name(topic) => check_topic(topic),
+        Target::Id(_) => bail!("MQTT topics are targeted by name, found {}", target),
+    }
+}
+
+/// Check that `topic` can be published to, i.e. without wildcards
+fn check_topic(topic: &str) -> Result<()> {
+    if topic.is_empty() || topic.len() > u16::MAX as usize {
+        bail!("Invalid length of topic {}", topic)
+    }
+    if topic.contains(['+', '#', '\0']) {
+        bail!("Wildcards are not allowed in topic {} to publish to", topic)
+    }
+    Ok(())
+}
+
+/// Replace characters not allowed in a level of topic
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    /// Last packet identifier used
+    packet_id: u16,
+}
+
+impl Connection {
+    async fn connect(config: &MqttConfig) -> Result<Self> {
+        let port = config.port.unwrap_or(if config.tls { 8883 } else { 1883 });
+        let stream = connect_stream(&config.host, port, config.tls).await?;
+        let mut conn = Self {
+            stream,
+            packet_id: 0,
+        };
+
+        // Clean session, with credentials if any
+        let mut flags = 0x02;
+        let mut body = vec![];
+        put_str(&mut body, "MQTT");
+        body.push(4);
+        if config.username.is_some() {
+            flags |= 0x80
+        }
+        if config.password.is_some() {
+            flags |= 0x40
+        }
+        body.push(flags);
+        body.extend_from_slice(&config.keep_alive.to_be_bytes());
+        put_str(&mut body, &config.client_id);
+        for x in [&config.username, &config.password].into_iter().flatten() {
+            put_str(&mut body, x)
+        }
+        conn.send(CONNECT << 4, &body).await?;
+
+        let (header, body) = conn.recv().await?;
+        match (header >> 4, body.get(1)) {
+            (CONNACK, Some(0)) => {}
+            (CONNACK, Some(code)) => bail!("Connection refused: {}", refused_reason(*code)),
+            _ => bail!("Unexpected packet from broker"),
+        }
+        info!("Connected to MQTT broker {}:{}", config.host, port);
+        Ok(conn)
+    }
+
+    async fn send(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        let mut packet = vec![header];
+        put_length(&mut packet, body.len());
+        packet.extend_from_slice(body);
+        self.stream
+            .write_all(&packet)
+            .await
+            .wrap_err("Failed to send to MQTT broker")
+    }
+
+    /// Next packet from broker, with its first byte
+    async fn recv(&mut self) -> Result<(u8, Vec<u8>)> {
+        timeout(RESPONSE_TIMEOUT, async {
+            let header = self.stream.read_u8().await?;
+            let mut len = 0;
+            for shift in (0..28).step_by(7) {
+                let byte = self.stream.read_u8().await?;
+                len |= ((byte & 0x7f) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0; len];
+            self.stream.read_exact(&mut body).await?;
+            Ok::<_, std::io::Error>((header, body))
+        })
+        .await
+        .wrap_err("Timeout waiting for MQTT broker")?
+        .wrap_err("Failed to read from MQTT broker")
+    }
+
+    /// Wait for packet of `kind` with packet identifier `id`
+    async fn expect(&mut self, kind: u8, id: u16) -> Result<()> {
+        loop {
+            let (header, body) = self.recv().await?;
+            if header >> 4 == kind && body.get(..2) == Some(&id.to_be_bytes()[..]) {
+                return Ok(());
+            }
+            debug!("Ignored packet of type {} from broker", header >> 4)
+        }
+    }
+
+    async fn ping(&mut self) -> Result<()> {
+        self.send(PINGREQ << 4, &[]).await?;
+        loop {
+            if self.recv().await?.0 >> 4 == PINGRESP {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Publish `payload` to `topic`, returning once broker has taken it as
+    /// required by QoS
+    async fn publish(&mut self, topic: &str, payload: &[u8], config: &MqttConfig) -> Result<()> {
+        self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+        let id = self.packet_id;
+
+        let mut body = vec![];
+        put_str(&mut body, topic);
+        if config.qos > 0 {
+            body.extend_from_slice(&id.to_be_bytes())
+        }
+        body.extend_from_slice(payload);
+        let header = PUBLISH << 4 | config.qos << 1 | config.retain as u8;
+        self.send(header, &body).await?;
+
+        match config.qos {
+            0 => {}
+            1 => self.expect(PUBACK, id).await?,
+            _ => {
+                self.expect(PUBREC, id).await?;
+                self.send(PUBREL << 4 | 0x02, &id.to_be_bytes()).await?;
+                self.expect(PUBCOMP, id).await?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append `s` prefixed with its length
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Append remaining length of a packet, 7 bits a byte
+fn put_length(buf: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80)
+    }
+}
+
+fn refused_reason(code: u8) -> &'static str {
+    match code {
+        1 => "unacceptable protocol version",
+        2 => "identifier rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorized",
+        _ => "unknown reason",
+    }
+}
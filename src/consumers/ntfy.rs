@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    by_index, fan_out, get_client, get_db, index_targets, permanent, retry_after, truncate_chars,
+    Consumer, DeadLetters, Event, EventKind, Inbox, NtfyConfig, Target, Templates,
+};
+
+/// Max length of message, as ntfy turns longer ones into attachments
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Consumer that publishes events to [ntfy](https://ntfy.sh) topics
+pub struct NtfyConsumer;
+
+impl NtfyConsumer {
+    /// Layouts of messages when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"{% if content -%}
+{% set text = content | html2text | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- elif name %}{{ name }}{% endif %}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"New update: {{ name }} {{ vers }}
+{%- if yanked %}
+Yanked: true
+{%- endif %}
+{%- if links %}
+Links: {{ links }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"{% if body -%}
+{% set text = body | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- else %}New {% if prerelease %}pre-release{% else %}release{% endif %} of {{ repo }}: {{ tag }}{% endif %}"#,
+        ),
+        (
+            EventKind::Git,
+            r#"{{ commit | truncate(length=7, end="") }}{% if branch %} on {{ branch }}{% endif %} by {{ author }}
+{%- if message %}
+{% set text = message | trim -%}
+{% if content_max_length > 0 %}{{ text | truncate_chars(length=content_max_length) }}{% else %}{{ text }}{% endif %}
+{%- endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for NtfyConsumer {
+    type Config = NtfyConfig;
+
+    const NAME: &'static str = "consumer_ntfy";
+
+    fn run(inbox: Inbox, config: NtfyConfig) -> JoinHandle<()> {
+        run_ntfy(inbox, config)
+    }
+}
+
+pub fn run_ntfy(mut inbox: Inbox, config: NtfyConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(NtfyConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        // Anyone knowing a topic can read it
+        let topics = index_targets(&config.topics);
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let msg = match templates.render(&envelope.event, &vars) {
+                Ok(x) => x,
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&topics), e);
+                    continue;
+                }
+            };
+            let payload = message(&envelope.event, &msg, &config);
+            debug!("Payload: {}", payload);
+
+            let targets = envelope.targets.resolve(&topics);
+            let failed = fan_out(targets, "Message published to topic", |topic| {
+                publish_to(topic, &payload, &config)
+            })
+            .await;
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// Build a message of `event` to be published as JSON, without its topic
+fn message(event: &Event, msg: &str, config: &NtfyConfig) -> Value {
+    let title = match event {
+        Event::Feed { name, title, .. } => title
+            .as_deref()
+            .or(name.as_deref())
+            .unwrap_or("Feed")
+            .to_owned(),
+        Event::CratesIo { name, vers, .. } => format!("{} {}", name, vers),
+        Event::GitHubRelease {
+            repo, tag, name, ..
+        } => format!("{} {}", repo, name.as_deref().unwrap_or(tag)),
+        Event::Git {
+            repo, tag, message, ..
+        } => match tag {
+            Some(tag) => format!("{} {}", repo, tag),
+            None => format!("{}: {}", repo, message.lines().next().unwrap_or_default()),
+        },
+    };
+    let mut payload = json!({
+        "title": title,
+        "message": truncate_chars(msg, MAX_MESSAGE_LENGTH),
+    });
+    if let Some(priority) = config.priority {
+        payload["priority"] = priority.into();
+    }
+    if !config.tags.is_empty() {
+        payload["tags"] = config.tags.clone().into();
+    }
+    // Opened when notification is tapped
+    if let Some(link) = event.link() {
+        payload["click"] = link.into();
+    }
+    payload
+}
+
+/// Publish `payload` to `topic`, by index in config, with retries
+async fn publish_to(topic: &Target, payload: &Value, config: &NtfyConfig) -> Result<()> {
+    let url = by_index(&config.topics, "topics", topic)?;
+    config.retry.retry(|| publish(url, payload, config)).await
+}
+
+async fn publish(url: &str, payload: &Value, config: &NtfyConfig) -> Result<()> {
+    // JSON messages are published to root of server, with topic in body, so
+    // that titles are not limited to what headers can carry
+    let (server, topic) = url
+        .trim_end_matches('/')
+        .rsplit_once('/')
+        .ok_or_else(|| eyre!("Invalid topic url"))
+        .map_err(permanent)?;
+    let mut payload = payload.clone();
+    payload["topic"] = topic.into();
+
+    let mut req = get_client().post(server).json(&payload);
+    if let Some(token) = &config.token {
+        req = req.bearer_auth(token)
+    }
+    let res = req.send().await.wrap_err("Failed to request ntfy")?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let delay = res
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs);
+    let text = res.text().await.unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(retry_after(e, delay.unwrap_or(Duration::from_secs(10))))
+    } else if status.is_client_error() {
+        Err(permanent(e))
+    } else {
+        Err(e)
+    }
+}
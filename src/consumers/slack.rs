@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use log::{debug, info, warn};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+
+use crate::{
+    by_index, escape_mrkdwn, fan_out, get_client, get_db, hide_url, index_targets, permanent,
+    retry_after, retry_after_of, truncate_chars, ts_to_systemtime, Consumer, DeadLetters, Event,
+    EventKind, Inbox, RateLimiter, SlackConfig, Target, Templates,
+};
+
+/// Max length of text of a header block
+const MAX_HEADER_LENGTH: usize = 150;
+
+/// Max length of text of a section block
+const MAX_SECTION_LENGTH: usize = 3000;
+
+/// Consumer that posts events to slack channels via incoming webhooks, as
+/// Block Kit messages
+pub struct SlackConsumer;
+
+impl SlackConsumer {
+    /// Layouts of sections when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"{% if content -%}
+{% set text = content | html2text | trim -%}
+{% if content_max_length > 0 %}{% set text = text | truncate_chars(length=content_max_length) %}{% endif -%}
+{{ text | escape_mrkdwn }}
+{% endif -%}
+{% if link %}<{{ link }}|Read more>{% endif %}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"New update: *{{ name }}* {{ vers }}
+{%- if yanked %}
+Yanked: true
+{%- endif %}
+{%- if links %}
+Links: {{ links | escape_mrkdwn }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"{% if body -%}
+{% set text = body | trim -%}
+{% if content_max_length > 0 %}{% set text = text | truncate_chars(length=content_max_length) %}{% endif -%}
+{{ text | escape_mrkdwn }}
+{% endif -%}
+<{{ link }}|Release notes>"#,
+        ),
+        (
+            EventKind::Git,
+            r#"`{{ commit | truncate(length=7, end="") }}`{% if branch %} on {{ branch | escape_mrkdwn }}{% endif %} by {{ author | escape_mrkdwn }}
+{%- if message %}
+{% set text = message | trim -%}
+{% if content_max_length > 0 %}{% set text = text | truncate_chars(length=content_max_length) %}{% endif -%}
+{{ text | escape_mrkdwn }}
+{%- endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for SlackConsumer {
+    type Config = SlackConfig;
+
+    const NAME: &'static str = "consumer_slack";
+
+    fn run(inbox: Inbox, config: SlackConfig) -> JoinHandle<()> {
+        run_slack(inbox, config)
+    }
+}
+
+pub fn run_slack(mut inbox: Inbox, config: SlackConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(SlackConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        let webhooks = index_targets(&config.webhooks);
+        let limiters = Mutex::new(HashMap::new());
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let section = match templates.render(&envelope.event, &vars) {
+                Ok(x) => x,
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&webhooks), e);
+                    continue;
+                }
+            };
+            let payload = message(&envelope.event, &section);
+            debug!("Payload: {}", payload);
+
+            let targets = envelope.targets.resolve(&webhooks);
+            let failed = fan_out(targets, "Message posted to webhook", |webhook| {
+                post_to(webhook, &payload, &config, &limiters)
+            })
+            .await;
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+/// Build a Block Kit message of `event`: a header, a section of `section` and
+/// a context of time and source
+fn message(event: &Event, section: &str) -> Value {
+    let (header, context) = match event {
+        Event::Feed {
+            feed,
+            name,
+            time,
+            title,
+            ..
+        } => {
+            let header = title.as_deref().or(name.as_deref()).unwrap_or("Feed");
+            let context = vec![
+                format!(
+                    "<!date^{}^{{date_short_pretty}} {{time}}|{}>",
+                    time,
+                    humantime::format_rfc3339(ts_to_systemtime(*time as u64))
+                ),
+                format!(
+                    "<{}|{}>",
+                    feed,
+                    escape_mrkdwn(name.as_deref().unwrap_or(feed))
+                ),
+            ];
+            (header.to_owned(), context)
+        }
+        Event::CratesIo { name, vers, .. } => (
+            format!("{} {}", name, vers),
+            vec![format!("<https://crates.io/crates/{}|Crates.io>", name)],
+        ),
+        Event::GitHubRelease {
+            repo, tag, name, ..
+        } => (
+            format!("{} {}", repo, name.as_deref().unwrap_or(tag)),
+            vec![format!("GitHub: {}", escape_mrkdwn(repo))],
+        ),
+        Event::Git {
+            repo, tag, time, ..
+        } => (
+            match tag {
+                Some(tag) => format!("{} {}", repo, tag),
+                None => repo.clone(),
+            },
+            vec![format!(
+                "<!date^{}^{{date_short_pretty}} {{time}}|{}>",
+                time,
+                humantime::format_rfc3339(ts_to_systemtime(*time as u64))
+            )],
+        ),
+    };
+
+    let mut blocks = vec![json!({
+        "type": "header",
+        "text": { "type": "plain_text", "text": truncate_chars(&header, MAX_HEADER_LENGTH) },
+    })];
+    if !section.trim().is_empty() {
+        blocks.push(json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": truncate_chars(section, MAX_SECTION_LENGTH) },
+        }));
+    }
+    blocks.push(json!({
+        "type": "context",
+        "elements": context
+            .into_iter()
+            .map(|x| json!({ "type": "mrkdwn", "text": x }))
+            .collect::<Vec<_>>(),
+    }));
+
+    // `text` is shown in notifications
+    json!({ "text": header, "blocks": blocks })
+}
+
+/// Post `payload` to `webhook`, by index in config, with retries, at most one
+/// message per second to each webhook
+async fn post_to(
+    webhook: &Target,
+    payload: &Value,
+    config: &SlackConfig,
+    limiters: &Mutex<HashMap<String, Arc<RateLimiter>>>,
+) -> Result<()> {
+    let url = by_index(&config.webhooks, "webhooks", webhook)?;
+    let limiter = limiters
+        .lock()
+        .unwrap()
+        .entry(url.to_owned())
+        .or_insert_with(|| Arc::new(RateLimiter::per(1, Duration::from_secs(1))))
+        .clone();
+    config
+        .retry
+        .retry(|| async {
+            limiter.wait().await;
+            let res = post(url, payload).await;
+            if let Some(delay) = res.as_ref().err().and_then(retry_after_of) {
+                limiter.pause(delay)
+            }
+            res
+        })
+        .await
+}
+
+async fn post(url: &str, payload: &Value) -> Result<()> {
+    let res = get_client()
+        .post(url)
+        .json(payload)
+        .send()
+        .await
+        .map_err(hide_url)
+        .wrap_err("Failed to request slack webhook")?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let delay = res
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|x| x.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs);
+    let text = res.text().await.unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(retry_after(e, delay.unwrap_or(Duration::from_secs(1))))
+    } else if status.is_client_error() {
+        Err(permanent(e))
+    } else {
+        Err(e)
+    }
+}
@@ -1,21 +1,132 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use color_eyre::{
     eyre::{eyre, Context},
-    Result,
+    Report, Result,
 };
 use futures::{stream::FuturesUnordered, StreamExt};
 use log::{debug, info, warn};
+use reqwest::StatusCode;
+use serde_json::json;
 use telegram_bot_raw::{self as tg, ChatId, ChatRef, SendMessage};
 use tg::GetMe;
 use tokio::task::JoinHandle;
 
-use crate::{get_client, Event, TelegramConfig, RX};
+use crate::{
+    get_client, get_db, permanent, retry_after, retry_after_of, split_html, Consumer, DeadLetters,
+    EventKind, Inbox, RateLimiter, Target, TelegramConfig, Templates,
+};
+
+/// Max length of a message, in characters
+const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Rate limits of telegram bot API, see
+/// <https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this>
+#[derive(Debug)]
+struct Limits {
+    /// 30 messages per second in total
+    bot: RateLimiter,
+    /// 1 message per second to each private chat, 20 per minute to each group
+    /// or channel
+    chats: Mutex<HashMap<Target, Arc<RateLimiter>>>,
+}
+
+impl Limits {
+    fn new() -> Self {
+        Self {
+            bot: RateLimiter::per(30, Duration::from_secs(1)),
+            chats: Mutex::default(),
+        }
+    }
+
+    async fn wait(&self, chat: &Target) {
+        let limiter = self
+            .chats
+            .lock()
+            .unwrap()
+            .entry(chat.clone())
+            .or_insert_with(|| {
+                Arc::new(match chat {
+                    Target::Id(id) if *id > 0 => RateLimiter::per(1, Duration::from_secs(1)),
+                    _ => RateLimiter::per(20, Duration::from_secs(60)),
+                })
+            })
+            .clone();
+        limiter.wait().await;
+        self.bot.wait().await;
+    }
+}
 
-pub fn run_telegram(mut rx: RX, config: TelegramConfig) -> JoinHandle<()> {
+/// Consumer that sends events to telegram chats via bot API
+pub struct TelegramConsumer;
+
+impl TelegramConsumer {
+    /// Layouts of messages when not configured
+    pub const TEMPLATES: &'static [(EventKind, &'static str)] = &[
+        (
+            EventKind::Feed,
+            r#"<b>[ {% if link %}<a href="{{ link }}">{% endif -%}
+{% if name %}{{ name | escape_html }}{% else %}Feed{% endif -%}
+{% if link %}</a>{% endif %} ]  {{ title | default(value="") | escape_html }}</b>
+{% if content %}
+{% if content_max_length > 0 and content | length >= content_max_length -%}
+{% set content = content | truncate(length=content_max_length - 1, end="...") -%}
+{% endif -%}
+{{ content | html2text | trim | escape_html }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::CratesIo,
+            r#"[ <a href="https://crates.io/crates/{{ name }}">Crates.io</a> ] New update: <b>{{ name }}</b>
+Version: {{ vers }}
+{%- if yanked %}
+Yanked: true
+{%- endif %}
+{%- if links %}
+Links: {{ links }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::GitHubRelease,
+            r#"[ {{ repo }} ] New {% if prerelease %}pre-release{% else %}release{% endif %}: <a href="{{ link }}"><b>{% if name %}{{ name | escape_html }}{% else %}{{ tag | escape_html }}{% endif %}</b></a>
+{%- if body %}
+{% if content_max_length > 0 and body | length >= content_max_length -%}
+{% set body = body | truncate(length=content_max_length - 1, end="...") -%}
+{% endif -%}
+{{ body | trim | escape_html }}
+{%- endif %}"#,
+        ),
+        (
+            EventKind::Git,
+            r#"[ {{ repo | escape_html }} ] {% if tag %}New tag: <b>{{ tag | escape_html }}</b>{% else %}New commit on <b>{{ branch | escape_html }}</b>{% endif %}
+<code>{{ commit | truncate(length=7, end="") }}</code> by {{ author | escape_html }}
+{%- if message %}
+{% if content_max_length > 0 and message | length >= content_max_length -%}
+{% set message = message | truncate(length=content_max_length - 1, end="...") -%}
+{% endif -%}
+{{ message | trim | escape_html }}
+{%- endif %}"#,
+        ),
+    ];
+}
+
+impl Consumer for TelegramConsumer {
+    type Config = TelegramConfig;
+
+    const NAME: &'static str = "consumer_telegram";
+
+    fn run(inbox: Inbox, config: TelegramConfig) -> JoinHandle<()> {
+        run_telegram(inbox, config)
+    }
+}
+
+pub fn run_telegram(mut inbox: Inbox, config: TelegramConfig) -> JoinHandle<()> {
     tokio::spawn(async move {
         let config = Arc::new(config);
-        let chats = config.chats.iter().map(|x| ChatRef::Id(ChatId::new(*x)));
 
         let me = send(GetMe, &config.api_token).await;
 
@@ -27,89 +138,89 @@ pub fn run_telegram(mut rx: RX, config: TelegramConfig) -> JoinHandle<()> {
             }
         }
 
-        while let Ok(res) = rx.recv().await {
-            match res {
-                Event::Feed {
-                    link,
-                    title,
-                    content,
-                    entry_id,
-                    ..
-                } => {
-                    info!("New Feed event: {}", entry_id);
-                    let (max_len, _) = config.content_max_length.overflowing_sub(1);
-
-                    let content = match content {
-                        Some(content) => {
-                            if content.len() > max_len {
-                                let bytes = content
-                                    .chars()
-                                    .take(max_len)
-                                    .chain("...\n\n".chars())
-                                    .map(|x| x as u8)
-                                    .collect::<Vec<_>>();
-
-                                html2text::from_read(bytes.deref(), 200) + "\n"
-                            } else {
-                                html2text::from_read(content.as_bytes(), 200) + "\n"
-                            }
-                        }
-                        None => String::new(),
-                    };
-
-                    let content = html_escape::encode_safe(&content);
-
-                    debug!("Content: {}", content);
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
 
-                    let link = if let Some(link) = link {
-                        format!("[ <a href=\"{}\">Feed</a> ]", link)
-                    } else {
-                        "[ Feed ]".to_owned()
-                    };
+        let templates = match Templates::new(TelegramConsumer::TEMPLATES, &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let vars = json!({ "content_max_length": config.content_max_length });
+        let limits = Limits::new();
 
-                    let msg = format!(
-                        "<b>{}  {}</b>\n\n{}",
-                        link,
-                        title.unwrap_or_default(),
-                        content,
-                    );
+        while let Some(envelope) = inbox.recv().await {
+            let chats = envelope.targets.resolve(&config.chats);
 
-                    send_msgs(chats.clone(), &msg, &config.api_token).await;
-                }
-                Event::CratesIo {
-                    name,
-                    vers,
-                    links,
-                    yanked,
-                } => {
-                    let yanked = if yanked { "\nYanked: true" } else { "" };
-                    let links = if let Some(links) = links {
-                        format!("\nLinks: {links}")
-                    } else {
-                        String::new()
-                    };
-                    let msg = format!(
-                        "[ <a href=\"https://crates.io/crates/{name}\">Crates.io</a> ] New \
-                         update: <b>{name}</b> \n Version: {vers}
-                         {yanked}
-                         {links}"
-                    );
-                    send_msgs(chats.clone(), &msg, &config.api_token).await;
+            info!("New event: {}", envelope.event);
+            let msg = match templates.render(&envelope.event, &vars) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    envelope.fail(&dead_letters, chats, e);
+                    continue;
                 }
-            }
+            };
+            debug!("Message: {}", msg);
+
+            let failed = send_msgs(chats, &msg, &config, &limits).await;
+            envelope.settle(&dead_letters, failed);
         }
     })
 }
 
-async fn send_msgs(chats: impl Iterator<Item = ChatRef>, msg: &str, token: &str) {
+/// Send `msg` to all `chats` with retries within rate limits, returning chats
+/// that failed. Long messages are sent in parts.
+async fn send_msgs<'a>(
+    chats: &'a [Target],
+    msg: &str,
+    config: &TelegramConfig,
+    limits: &Limits,
+) -> Vec<(&'a Target, Report)> {
+    let parts = &split_html(msg, MAX_MESSAGE_LENGTH);
+    if parts.len() > 1 {
+        debug!("Message is split into {} parts", parts.len())
+    }
+
+    let mut failed = vec![];
     let mut stream = chats
-        .map(|chat| {
-            let mut msg = SendMessage::new(chat, msg);
-            msg.parse_mode(tg::ParseMode::Html);
-            send(msg, token)
+        .iter()
+        .map(|chat| async move {
+            let chat_ref = match chat {
+                Target::Id(id) => ChatRef::Id(ChatId::new(*id)),
+                Target::Name(name) => ChatRef::ChannelUsername(name.to_owned()),
+            };
+            let mut sent = None;
+            for part in parts {
+                let res = config
+                    .retry
+                    .retry(|| async {
+                        let mut msg = SendMessage::new(chat_ref.clone(), part);
+                        msg.parse_mode(tg::ParseMode::Html);
+                        limits.wait(chat).await;
+                        let res = send(msg, &config.api_token).await;
+                        // Flood control applies to the whole bot, hold back other chats too
+                        if let Some(delay) = res.as_ref().err().and_then(retry_after_of) {
+                            limits.bot.pause(delay)
+                        }
+                        res
+                    })
+                    .await;
+                match res {
+                    Ok(res) => sent = Some(res),
+                    Err(e) => return (chat, Err(e)),
+                }
+            }
+            (chat, sent.ok_or_else(|| eyre!("Message is empty")))
         })
         .collect::<FuturesUnordered<_>>();
-    while let Some(res) = stream.next().await {
+    while let Some((chat, res)) = stream.next().await {
         match res {
             Ok(res) => info!(
                 "Message sent to chat ({})",
@@ -118,9 +229,10 @@ async fn send_msgs(chats: impl Iterator<Item = ChatRef>, msg: &str, token: &str)
                     tg::MessageOrChannelPost::ChannelPost(post) => post.chat.id.to_string(),
                 }
             ),
-            Err(e) => warn!("{:?}", e),
+            Err(e) => failed.push((chat, e)),
         }
     }
+    failed
 }
 
 async fn send<Req: tg::Request>(
@@ -136,15 +248,33 @@ async fn send<Req: tg::Request>(
 
     if !status.is_success() {
         let text = res.text().await.wrap_err("Decode failed")?;
-        Err(eyre!("{}", text).wrap_err(format!(
+        let e = eyre!("{}", text).wrap_err(format!(
             "Unsuccessful response from server (Code: {})",
             status
-        )))
+        ));
+        // Client errors other than rate limiting won't go away by retrying
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            match parse_retry_after(&text) {
+                Some(delay) => Err(retry_after(e, delay)),
+                None => Err(e),
+            }
+        } else if status.is_client_error() {
+            Err(permanent(e))
+        } else {
+            Err(e)
+        }
     } else {
         reqwest_res_to_tg_raw::<Req::Response>(res).await
     }
 }
 
+/// Read `parameters.retry_after` of an error response
+pub fn parse_retry_after(body: &str) -> Option<Duration> {
+    serde_json::from_str::<serde_json::Value>(body).ok()?["parameters"]["retry_after"]
+        .as_u64()
+        .map(Duration::from_secs)
+}
+
 async fn tg_raw_to_reqwest(tg_req: tg::HttpRequest, token: &str) -> Result<reqwest::Response> {
     let tg::HttpRequest { url, method, body } = tg_req;
     let method = match method {
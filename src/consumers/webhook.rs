@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use reqwest::{header::CONTENT_TYPE, StatusCode};
+use serde_json::json;
+use sha2::Sha256;
+use tokio::task::JoinHandle;
+
+use crate::{
+    check_secs, fan_out, get_client, get_db, permanent, retry_after, Consumer, DeadLetters,
+    Envelope, EventRecord, Hints, Inbox, Route, Target, Templates, WebhookConfig,
+};
+
+/// Consumer that POSTs events to arbitrary urls, as JSON or templated bodies
+pub struct WebhookConsumer;
+
+impl Consumer for WebhookConsumer {
+    type Config = WebhookConfig;
+
+    const NAME: &'static str = "consumer_webhook";
+
+    fn validate(config: &WebhookConfig, _routes: &[Route]) -> Result<()> {
+        check_secs("timeout", config.timeout)
+    }
+
+    fn run(inbox: Inbox, config: WebhookConfig) -> JoinHandle<()> {
+        run_webhook(inbox, config)
+    }
+}
+
+/// A request body with its content type
+struct Payload {
+    body: Vec<u8>,
+    content_type: String,
+    signature: Option<String>,
+}
+
+pub fn run_webhook(mut inbox: Inbox, config: WebhookConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let dead_letters = match DeadLetters::open(get_db()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to open dead letters: {}", e);
+                return;
+            }
+        };
+        let templates = match Templates::new(&[], &config.templates) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let urls = config
+            .urls
+            .iter()
+            .cloned()
+            .map(Target::Name)
+            .collect::<Vec<_>>();
+
+        while let Some(envelope) = inbox.recv().await {
+            info!("New event: {}", envelope.event);
+            let payload = match payload(&envelope, &templates, &config) {
+                Ok(x) => x,
+                Err(e) => {
+                    envelope.fail(&dead_letters, envelope.targets.resolve(&urls), e);
+                    continue;
+                }
+            };
+            debug!("Payload: {}", String::from_utf8_lossy(&payload.body));
+
+            let targets = envelope.targets.resolve(&urls);
+            let failed = fan_out(targets, "Event posted to webhook", |url| {
+                post_to(url, envelope.id, &payload, &config)
+            })
+            .await;
+            envelope.settle(&dead_letters, failed);
+        }
+    })
+}
+
+fn payload(envelope: &Envelope, templates: &Templates, config: &WebhookConfig) -> Result<Payload> {
+    let (body, content_type) = if config.templates.contains_key(&envelope.event.kind()) {
+        (
+            templates.render(&envelope.event, json!({ "id": envelope.id }))?,
+            config.content_type.clone(),
+        )
+    } else {
+        let body = serde_json::to_string(&EventRecord(envelope.event.clone()))
+            .wrap_err("Failed to serialize event")?;
+        (body, "application/json".to_owned())
+    };
+
+    let signature = config
+        .secret
+        .as_ref()
+        .map(|secret| sign(secret, body.as_bytes()))
+        .transpose()?;
+
+    Ok(Payload {
+        body: body.into_bytes(),
+        content_type,
+        signature,
+    })
+}
+
+/// Signature of `body` as `sha256=<hex of HMAC-SHA256>`
+pub fn sign(secret: &str, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| eyre!("Invalid secret: {}", e))?;
+    mac.update(body);
+    Ok(format!(
+        "sha256={}",
+        hex::encode(mac.finalize().into_bytes())
+    ))
+}
+
+/// POST `payload` to `url` with retries
+async fn post_to(url: &Target, id: u64, payload: &Payload, config: &WebhookConfig) -> Result<()> {
+    match url {
+        Target::Name(url) => config.retry.retry(|| post(url, id, payload, config)).await,
+        Target::Id(_) => Err(eyre!("Webhooks are targeted by url")),
+    }
+}
+
+async fn post(url: &str, id: u64, payload: &Payload, config: &WebhookConfig) -> Result<()> {
+    let mut req = get_client()
+        .post(url)
+        .timeout(Duration::from_secs_f64(config.timeout))
+        .header(CONTENT_TYPE, &payload.content_type)
+        // Lets receivers drop duplicates of retried deliveries
+        .header("X-Caster-Delivery", id.to_string())
+        .body(payload.body.clone());
+    for (key, value) in config.headers.iter() {
+        req = req.header(key, value)
+    }
+    if let Some(signature) = &payload.signature {
+        req = req.header(&config.signature_header, signature)
+    }
+
+    let res = req.send().await.wrap_err("Failed to request webhook")?;
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+
+    let hints = Hints::from_headers(res.headers());
+    let text = res.text().await.unwrap_or_default();
+    let e = eyre!("{}", text).wrap_err(format!(
+        "Unsuccessful response from server (Code: {})",
+        status
+    ));
+    match hints.retry_after {
+        Some(delay) if status == StatusCode::TOO_MANY_REQUESTS => Err(retry_after(e, delay)),
+        _ if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS => {
+            Err(permanent(e))
+        }
+        _ => Err(e),
+    }
+}
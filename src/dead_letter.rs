@@ -0,0 +1,99 @@
+use std::time::SystemTime;
+
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+
+use crate::{Event, OutboxStore, Target};
+
+/// An event a consumer failed to deliver after all retries
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// Name of consumer, e.g. `consumer_telegram`
+    pub consumer: String,
+    pub event: Event,
+    /// Targets the event failed to be delivered to
+    pub targets: Vec<Target>,
+    /// Last error
+    pub error: String,
+    /// Unix timestamp of failure
+    pub time: i64,
+}
+
+/// Store of [`DeadLetter`]s, which can be inspected and replayed
+#[derive(Debug, Clone)]
+pub struct DeadLetters {
+    tree: Tree,
+    db: Db,
+}
+
+impl DeadLetters {
+    pub fn open(db: &Db) -> Result<Self> {
+        Ok(Self {
+            tree: db.open_tree("dead_letters")?,
+            db: db.clone(),
+        })
+    }
+
+    pub fn push(
+        &self,
+        consumer: &str,
+        event: Event,
+        targets: Vec<Target>,
+        error: String,
+    ) -> Result<u64> {
+        let id = self.db.generate_id()?;
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let letter = DeadLetter {
+            id,
+            consumer: consumer.to_owned(),
+            event,
+            targets,
+            error,
+            time,
+        };
+        self.tree.insert(
+            id.to_be_bytes(),
+            serde_json::to_vec(&letter).wrap_err("Failed to serialize dead letter")?,
+        )?;
+        self.tree.flush()?;
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Result<Vec<DeadLetter>> {
+        self.tree
+            .iter()
+            .values()
+            .map(|x| serde_json::from_slice(&x?).wrap_err("Failed to deserialize dead letter"))
+            .collect()
+    }
+
+    pub fn remove(&self, id: u64) -> Result<Option<DeadLetter>> {
+        self.tree
+            .remove(id.to_be_bytes())?
+            .map(|x| serde_json::from_slice(&x).wrap_err("Failed to deserialize dead letter"))
+            .transpose()
+    }
+
+    /// Move dead letter `id` back to `outbox`, to be delivered again to its
+    /// consumer and targets
+    pub fn replay(&self, id: u64, outbox: &OutboxStore) -> Result<()> {
+        let letter = self
+            .tree
+            .get(id.to_be_bytes())?
+            .ok_or_else(|| eyre!("Dead letter #{} does not exist", id))?;
+        let letter: DeadLetter =
+            serde_json::from_slice(&letter).wrap_err("Failed to deserialize dead letter")?;
+
+        outbox.requeue(&letter.consumer, letter.event, letter.targets)?;
+        self.tree.remove(id.to_be_bytes())?;
+        self.tree.flush()?;
+        Ok(())
+    }
+}
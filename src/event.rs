@@ -1,12 +1,18 @@
 use std::fmt::Display;
 
 use humantime::format_rfc3339;
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 
 use crate::ts_to_systemtime;
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Event {
     Feed {
+        /// Url of the feed this entry belongs to
+        feed: String,
+        /// Name of the feed, if configured
+        name: Option<String>,
         entry_id: String,
         time: i64,
         content: Option<String>,
@@ -19,6 +25,66 @@ pub enum Event {
         links: Option<String>,
         yanked: bool,
     },
+    GitHubRelease {
+        /// Repository, as `owner/name`
+        repo: String,
+        tag: String,
+        /// Title of the release, `None` for a tag without release
+        name: Option<String>,
+        /// Release notes in markdown
+        body: Option<String>,
+        link: String,
+        prerelease: bool,
+        time: i64,
+    },
+    Git {
+        /// Name of the repository if configured, otherwise its url
+        repo: String,
+        /// Branch of a new commit, `None` for a new tag
+        branch: Option<String>,
+        /// Name of a new tag, `None` for a new commit
+        tag: Option<String>,
+        /// Id of the commit, or of the commit tagged
+        commit: String,
+        author: String,
+        /// Message of the commit, or of an annotated tag
+        message: String,
+        time: i64,
+    },
+}
+
+/// Kind of an [`Event`], without its content
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Feed,
+    CratesIo,
+    #[serde(rename = "github_release")]
+    GitHubRelease,
+    Git,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::Feed { .. } => EventKind::Feed,
+            Event::CratesIo { .. } => EventKind::CratesIo,
+            Event::GitHubRelease { .. } => EventKind::GitHubRelease,
+            Event::Git { .. } => EventKind::Git,
+        }
+    }
+
+    /// Url of web page of this event, e.g. link of feed entry
+    pub fn link(&self) -> Option<String> {
+        match self {
+            Event::Feed { link, .. } => link.clone(),
+            Event::CratesIo { name, vers, .. } => {
+                Some(format!("https://crates.io/crates/{}/{}", name, vers))
+            }
+            Event::GitHubRelease { link, .. } => Some(link.clone()),
+            Event::Git { .. } => None,
+        }
+    }
 }
 
 impl Display for Event {
@@ -33,6 +99,77 @@ impl Display for Event {
                 )
             }
             Event::CratesIo { name, .. } => write!(f, "Crates.io event: {} ", name),
+            Event::GitHubRelease { repo, tag, .. } => {
+                write!(f, "GitHub release event: {} {}", repo, tag)
+            }
+            Event::Git {
+                repo, tag, commit, ..
+            } => match tag {
+                Some(tag) => write!(f, "Git tag event: {} {}", repo, tag),
+                None => write!(f, "Git commit event: {} {}", repo, commit),
+            },
+        }
+    }
+}
+
+/// Version of schema of [`EventRecord`], bumped on breaking changes
+pub const EVENT_SCHEMA_VERSION: u64 = 1;
+
+/// An [`Event`] in its stable JSON schema, for other tools: fields of the
+/// event along with its `kind` and schema `version`, e.g.
+/// `{"version":1,"kind":"crates_io","name":"foo","vers":"1.0.0",...}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRecord(pub Event);
+
+impl Serialize for EventRecord {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        // Externally tagged, fields are in the only value
+        let fields = match serde_json::to_value(&self.0).map_err(ser::Error::custom)? {
+            Value::Object(map) => map.into_iter().next().map(|(_, x)| x),
+            _ => None,
+        };
+        let mut record = Map::new();
+        record.insert("version".to_owned(), EVENT_SCHEMA_VERSION.into());
+        record.insert(
+            "kind".to_owned(),
+            serde_json::to_value(self.0.kind()).map_err(ser::Error::custom)?,
+        );
+        if let Some(Value::Object(fields)) = fields {
+            record.extend(fields)
         }
+        record.serialize(ser)
+    }
+}
+
+impl<'de> Deserialize<'de> for EventRecord {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let mut record = Map::deserialize(de)?;
+        match record.remove("version").and_then(|x| x.as_u64()) {
+            Some(version) if version <= EVENT_SCHEMA_VERSION => {}
+            Some(version) => {
+                return Err(de::Error::custom(format!(
+                    "unsupported event schema version {}",
+                    version
+                )))
+            }
+            None => return Err(de::Error::missing_field("version")),
+        }
+        let kind: EventKind = record
+            .remove("kind")
+            .map(serde_json::from_value)
+            .ok_or_else(|| de::Error::missing_field("kind"))?
+            .map_err(de::Error::custom)?;
+        let variant = match kind {
+            EventKind::Feed => "Feed",
+            EventKind::CratesIo => "CratesIo",
+            EventKind::GitHubRelease => "GitHubRelease",
+            EventKind::Git => "Git",
+        };
+
+        let mut event = Map::new();
+        event.insert(variant.to_owned(), Value::Object(record));
+        serde_json::from_value(Value::Object(event))
+            .map(Self)
+            .map_err(de::Error::custom)
     }
 }
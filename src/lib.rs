@@ -0,0 +1,53 @@
+//! Caster collects updates from sources like RSS feeds and crates.io
+//! ([`Caster`]s) and pushes them as [`Event`]s to sinks like telegram
+//! ([`Consumer`]s).
+
+use std::sync::Arc;
+
+use color_eyre::{eyre::Context, Result};
+use futures::future::join;
+
+mod_use::mod_use!(
+    utils,
+    config,
+    event,
+    storage,
+    outbox,
+    dead_letter,
+    retry,
+    rate_limit,
+    template,
+    registry,
+    router,
+    casters,
+    consumers,
+);
+
+#[cfg(test)]
+mod test;
+
+/// Run all casters and consumers in `registry` configured in `config`, until
+/// all of them stopped.
+pub async fn run(config: Arc<Config>, registry: Registry) -> Result<()> {
+    let db = open_db(&config.db_path)?;
+    let outbox = Outbox::open(
+        db.open_tree("outbox")?,
+        registry.active_consumers(&config),
+        config.channel_size,
+    )?;
+
+    match join(
+        run_casters(outbox.clone(), config.clone(), &registry),
+        run_consumer(outbox, config, &registry),
+    )
+    .await
+    {
+        (Err(e1), Err(e2)) => Err(e1
+            .wrap_err("Caster error")
+            .wrap_err(e2)
+            .wrap_err("Consumer error")),
+        (_, Err(e)) => Err(e).wrap_err("Consumer error"),
+        (Err(e), _) => Err(e).wrap_err("Caster error"),
+        _ => Ok(()),
+    }
+}
@@ -1,37 +1,97 @@
 use std::{env, sync::Arc};
 
-use color_eyre::{eyre::Context, Result};
-use futures::future::join;
-use tokio::sync::broadcast;
+use caster::{init, open_db, run, ts_to_humantime, DeadLetters, Outbox, Registry};
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
 
-mod_use::mod_use!(utils, config, event, casters, consumers,);
-
-#[cfg(test)]
-mod test;
+const USAGE: &str = "\
+Usage:
+    caster [CONFIG]                                 Run caster
+    caster dead-letters list [CONFIG]               List dead letters
+    caster dead-letters replay <ID|all> [CONFIG]    Deliver dead letters again on next run
+    caster dead-letters remove <ID|all> [CONFIG]    Remove dead letters";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    run().await
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    match args[..] {
+        ["dead-letters", "list", ref config @ ..] if config.len() <= 1 => {
+            list_dead_letters(config.first().copied())
+        }
+        ["dead-letters", op @ ("replay" | "remove"), id, ref config @ ..] if config.len() <= 1 => {
+            edit_dead_letters(op, id, config.first().copied())
+        }
+        ["-h" | "--help", ..] => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        [] => run_caster(None).await,
+        [config] if !config.starts_with('-') && config != "dead-letters" => {
+            run_caster(Some(config)).await
+        }
+        _ => bail!("Invalid arguments\n\n{}", USAGE),
+    }
+}
+
+async fn run_caster(config_path: Option<&str>) -> Result<()> {
+    let config = Arc::new(init(config_path)?);
+
+    run(config, Registry::new()).await
+}
+
+fn list_dead_letters(config_path: Option<&str>) -> Result<()> {
+    let config = init(config_path)?;
+    let dead_letters = DeadLetters::open(open_db(&config.db_path)?)?;
+
+    for letter in dead_letters.list()? {
+        let targets = letter
+            .targets
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        println!(
+            "#{} [{}] {} -> {} ({})\n    {}",
+            letter.id,
+            ts_to_humantime(letter.time as u64),
+            letter.event,
+            letter.consumer,
+            targets.join(", "),
+            letter.error
+        );
+    }
+
+    Ok(())
 }
 
-async fn run() -> Result<()> {
-    let config_dir = env::args().nth(1);
-    let config = Arc::new(init(config_dir.as_deref())?);
-
-    let (tx, _) = broadcast::channel(config.channel_size);
-
-    match join(
-        run_casters(tx.clone(), config.clone()),
-        run_consumer(tx, config),
-    )
-    .await
-    {
-        (Err(e1), Err(e2)) => Err(e1
-            .wrap_err("Caster error")
-            .wrap_err(e2)
-            .wrap_err("Consumer error")),
-        (_, Err(e)) => Err(e).wrap_err("Consumer error"),
-        (Err(e), _) => Err(e).wrap_err("Caster error"),
-        _ => Ok(()),
+fn edit_dead_letters(op: &str, id: &str, config_path: Option<&str>) -> Result<()> {
+    let config = init(config_path)?;
+    let db = open_db(&config.db_path)?;
+    let dead_letters = DeadLetters::open(db)?;
+    let outbox = Outbox::open(
+        db.open_tree("outbox")?,
+        Registry::new().active_consumers(&config),
+        config.channel_size,
+    )?;
+
+    let ids = if id == "all" {
+        dead_letters.list()?.into_iter().map(|x| x.id).collect()
+    } else {
+        vec![id.parse().wrap_err_with(|| format!("Invalid ID: {}", id))?]
+    };
+
+    for id in ids {
+        if op == "replay" {
+            dead_letters.replay(id, outbox.store())?;
+            println!("Dead letter #{} will be delivered on next run", id);
+        } else {
+            dead_letters.remove(id)?;
+            println!("Dead letter #{} removed", id);
+        }
     }
+
+    Ok(())
 }
@@ -0,0 +1,240 @@
+use std::sync::{Arc, Mutex};
+
+use color_eyre::{
+    eyre::{bail, Context},
+    Result,
+};
+use log::{debug, warn};
+use sled::{Batch, Tree};
+use tokio::sync::broadcast;
+
+use crate::{Event, Target};
+
+/// An event persisted in [`Outbox`], identified by `id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queued {
+    pub id: u64,
+    pub event: Event,
+    /// Targets to deliver to regardless of routes, e.g. for replayed events
+    pub targets: Option<Vec<Target>>,
+}
+
+/// Durable queue between casters and consumers. Every event sent is persisted
+/// along with a pending mark for each consumer, which is removed once the
+/// consumer acknowledges it. Unacknowledged events are redelivered on restart.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    store: OutboxStore,
+    tx: broadcast::Sender<Queued>,
+    /// Held from allocating id to broadcasting, so that events are broadcast
+    /// in order of their ids
+    sending: Arc<Mutex<()>>,
+}
+
+/// Storage of [`Outbox`], used to acknowledge events
+#[derive(Debug, Clone)]
+pub struct OutboxStore {
+    tree: Tree,
+    consumers: Arc<[&'static str]>,
+}
+
+impl Outbox {
+    /// Open outbox in `tree` for `consumers`. Events pending for consumers not
+    /// in `consumers` are dropped.
+    pub fn open(tree: Tree, consumers: Vec<&'static str>, size: usize) -> Result<Self> {
+        let store = OutboxStore {
+            tree,
+            consumers: consumers.into(),
+        };
+        store.cleanup()?;
+
+        let (tx, _) = broadcast::channel(size);
+        Ok(Self {
+            store,
+            tx,
+            sending: Arc::default(),
+        })
+    }
+
+    /// Persist `event` and broadcast it to consumers. The event is flushed to
+    /// disk before returning, so casters should mark it as seen only after
+    /// this succeeds.
+    pub fn send(&self, event: Event) -> Result<u64> {
+        let _sending = self.sending.lock().unwrap();
+        let id = self.store.next_id()?;
+        let mut batch = Batch::default();
+        batch.insert(
+            event_key(id),
+            serde_json::to_vec(&event).wrap_err("Failed to serialize event")?,
+        );
+        for consumer in self.store.consumers.iter() {
+            batch.insert(pending_key(consumer, id), &[]);
+        }
+        self.store
+            .tree
+            .apply_batch(batch)
+            .wrap_err("Failed to persist event")?;
+        self.store.tree.flush()?;
+
+        let queued = Queued {
+            id,
+            event,
+            targets: None,
+        };
+        if self.tx.send(queued).is_err() {
+            debug!("No consumer is listening, event #{} stays in outbox", id)
+        }
+
+        Ok(id)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Queued> {
+        self.tx.subscribe()
+    }
+
+    pub fn store(&self) -> &OutboxStore {
+        &self.store
+    }
+}
+
+impl OutboxStore {
+    /// Events not yet acknowledged by `consumer`, in order they were sent
+    pub fn pending(&self, consumer: &str) -> Result<Vec<Queued>> {
+        let mut res = vec![];
+        for item in self.tree.scan_prefix(pending_prefix(consumer)) {
+            let (key, targets) = item?;
+            let id = id_of(&key);
+            let targets = if targets.is_empty() {
+                None
+            } else {
+                Some(serde_json::from_slice(&targets).wrap_err("Failed to deserialize targets")?)
+            };
+            match self.get(id)? {
+                Some(event) => res.push(Queued { id, event, targets }),
+                None => {
+                    warn!("Pending event #{} is missing, dropped", id);
+                    self.tree.remove(key)?;
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Persist `event` to be delivered only to `consumer` and `targets` on
+    /// next start
+    pub fn requeue(&self, consumer: &str, event: Event, targets: Vec<Target>) -> Result<u64> {
+        if !self.consumers.contains(&consumer) {
+            bail!("Consumer `{}` is not configured", consumer)
+        }
+        let id = self.next_id()?;
+        let mut batch = Batch::default();
+        batch.insert(
+            event_key(id),
+            serde_json::to_vec(&event).wrap_err("Failed to serialize event")?,
+        );
+        batch.insert(
+            pending_key(consumer, id),
+            serde_json::to_vec(&targets).wrap_err("Failed to serialize targets")?,
+        );
+        self.tree
+            .apply_batch(batch)
+            .wrap_err("Failed to persist event")?;
+        self.tree.flush()?;
+        Ok(id)
+    }
+
+    /// Count `n` events `consumer` missed from broadcast, returning the total
+    /// so far
+    pub fn record_missed(&self, consumer: &str, n: u64) -> Result<u64> {
+        let total = self.tree.update_and_fetch(missed_key(consumer), |old| {
+            let total = old.map(id_of).unwrap_or_default() + n;
+            Some(total.to_be_bytes().to_vec())
+        })?;
+        Ok(total.as_deref().map(id_of).unwrap_or_default())
+    }
+
+    /// Number of events `consumer` missed from broadcast and caught up from
+    /// storage
+    pub fn missed(&self, consumer: &str) -> Result<u64> {
+        Ok(self
+            .tree
+            .get(missed_key(consumer))?
+            .as_deref()
+            .map(id_of)
+            .unwrap_or_default())
+    }
+
+    fn next_id(&self) -> Result<u64> {
+        let id = self.tree.update_and_fetch(b"id", |old| {
+            let id = old.map(id_of).unwrap_or_default() + 1;
+            Some(id.to_be_bytes().to_vec())
+        })?;
+        Ok(id.as_deref().map(id_of).unwrap_or_default())
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<Event>> {
+        self.tree
+            .get(event_key(id))?
+            .map(|x| serde_json::from_slice(&x).wrap_err("Failed to deserialize event"))
+            .transpose()
+    }
+
+    /// Mark event `id` as delivered to `consumer`. The event is removed once
+    /// all consumers acknowledged it.
+    pub fn ack(&self, consumer: &str, id: u64) -> Result<()> {
+        self.tree.remove(pending_key(consumer, id))?;
+        if !self.is_pending(id)? {
+            self.tree.remove(event_key(id))?;
+        }
+        Ok(())
+    }
+
+    fn is_pending(&self, id: u64) -> Result<bool> {
+        for consumer in self.consumers.iter() {
+            if self.tree.contains_key(pending_key(consumer, id))? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn cleanup(&self) -> Result<()> {
+        for item in self.tree.scan_prefix(b"pending/") {
+            let (key, _) = item?;
+            let consumer = &key[b"pending/".len()..key.len() - 9];
+            if !self.consumers.iter().any(|x| x.as_bytes() == consumer) {
+                self.tree.remove(key)?;
+            }
+        }
+        for item in self.tree.scan_prefix(b"event/") {
+            let (key, _) = item?;
+            let id = id_of(&key);
+            if !self.is_pending(id)? {
+                self.tree.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn event_key(id: u64) -> Vec<u8> {
+    [b"event/".as_ref(), &id.to_be_bytes()].concat()
+}
+
+fn pending_prefix(consumer: &str) -> Vec<u8> {
+    format!("pending/{}/", consumer).into_bytes()
+}
+
+fn pending_key(consumer: &str, id: u64) -> Vec<u8> {
+    [pending_prefix(consumer), id.to_be_bytes().to_vec()].concat()
+}
+
+fn missed_key(consumer: &str) -> Vec<u8> {
+    format!("missed/{}", consumer).into_bytes()
+}
+
+fn id_of(key: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&key[key.len() - 8..]);
+    u64::from_be_bytes(id)
+}
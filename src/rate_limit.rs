@@ -0,0 +1,48 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::{sleep_until, Instant};
+
+/// Limits how often something happens: at most `burst` at once, and one per
+/// `interval` on average. Callers that exceed it wait in line.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    burst: u32,
+    /// Time when next call would be allowed if there was no burst
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration, burst: u32) -> Self {
+        Self {
+            interval,
+            burst: burst.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// At most `n` calls per `period`, evenly spaced
+    pub fn per(n: u32, period: Duration) -> Self {
+        Self::new(period / n.max(1), 1)
+    }
+
+    /// Wait for turn
+    pub async fn wait(&self) {
+        let at = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let at = (*next).max(now);
+            *next = at + self.interval;
+            at.checked_sub(self.interval * (self.burst - 1))
+                .unwrap_or(now)
+        };
+        sleep_until(at).await
+    }
+
+    /// Let no call through for `duration`, e.g. when server asks to slow down
+    pub fn pause(&self, duration: Duration) {
+        let mut next = self.next.lock().unwrap();
+        let until = Instant::now() + duration + self.interval * (self.burst - 1);
+        *next = (*next).max(until);
+    }
+}
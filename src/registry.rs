@@ -0,0 +1,139 @@
+use color_eyre::{eyre::Context, Result};
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{
+    Caster, Config, Consumer, CratesCaster, DiscordConsumer, EmailConsumer, FeedCaster, FeedConfig,
+    FeedServerConsumer, GitCaster, GitHubCaster, GotifyConsumer, Inbox, IrcConsumer, JsonlConsumer,
+    MastodonConsumer, MatrixConsumer, MqttConsumer, NtfyConsumer, Outbox, Router, SlackConsumer,
+    TelegramConsumer, WebhookConsumer,
+};
+
+type Spawner<T> = Box<dyn Fn(T, &Config) -> Result<Option<JoinHandle<()>>> + Send + Sync>;
+
+/// Casters and consumers known to caster, keyed by their config section name.
+/// Only those with a section present in [`Config`] will be started.
+pub struct Registry {
+    casters: Vec<(&'static str, Spawner<Outbox>)>,
+    consumers: Vec<(&'static str, Spawner<Inbox>)>,
+}
+
+impl Registry {
+    /// An empty registry, without any builtin caster or consumer
+    pub fn empty() -> Self {
+        Self {
+            casters: vec![],
+            consumers: vec![],
+        }
+    }
+
+    /// A registry with all builtin casters and consumers
+    pub fn new() -> Self {
+        Self::empty()
+            .caster::<FeedCaster>()
+            .caster::<CratesCaster>()
+            .caster::<GitHubCaster>()
+            .caster::<GitCaster>()
+            .consumer::<TelegramConsumer>()
+            .consumer::<DiscordConsumer>()
+            .consumer::<SlackConsumer>()
+            .consumer::<MatrixConsumer>()
+            .consumer::<EmailConsumer>()
+            .consumer::<WebhookConsumer>()
+            .consumer::<JsonlConsumer>()
+            .consumer::<FeedServerConsumer>()
+            .consumer::<NtfyConsumer>()
+            .consumer::<GotifyConsumer>()
+            .consumer::<IrcConsumer>()
+            .consumer::<MastodonConsumer>()
+            .consumer::<MqttConsumer>()
+    }
+
+    pub fn caster<C: Caster>(mut self) -> Self {
+        self.casters.retain(|(name, _)| *name != C::NAME);
+        self.casters.push((
+            C::NAME,
+            Box::new(|tx, config| Ok(config.section(C::NAME)?.map(|c| C::run(tx, c)))),
+        ));
+        self
+    }
+
+    pub fn consumer<C: Consumer>(mut self) -> Self {
+        self.consumers.retain(|(name, _)| *name != C::NAME);
+        self.consumers.push((
+            C::NAME,
+            Box::new(|inbox, config| {
+                config
+                    .section(C::NAME)?
+                    .map(|c| {
+                        C::validate(&c, &config.routes)
+                            .wrap_err_with(|| format!("Invalid config section `{}`", C::NAME))?;
+                        Ok(C::run(inbox, c))
+                    })
+                    .transpose()
+            }),
+        ));
+        self
+    }
+
+    /// Names of consumers that are configured in `config`
+    pub fn active_consumers(&self, config: &Config) -> Vec<&'static str> {
+        self.consumers
+            .iter()
+            .map(|(name, _)| *name)
+            .filter(|name| config.sections.contains_key(*name))
+            .collect()
+    }
+
+    pub fn spawn_casters(&self, tx: &Outbox, config: &Config) -> Result<Vec<JoinHandle<()>>> {
+        self.warn_unknown(config);
+        let mut handles = vec![];
+        for (name, spawn) in self.casters.iter() {
+            if let Some(handle) = spawn(tx.clone(), config)? {
+                info!("Started `{}`", name);
+                handles.push(handle)
+            }
+        }
+        Ok(handles)
+    }
+
+    /// Spawn consumers, each receiving events routed to it by [`Router`]
+    pub fn spawn_consumers(&self, outbox: &Outbox, config: &Config) -> Result<Vec<JoinHandle<()>>> {
+        // Chats of feeds take place of chats in config of telegram consumer
+        let overrides = config
+            .section::<FeedConfig>(FeedCaster::NAME)?
+            .map(|x| x.routes())
+            .unwrap_or_default();
+        let router = Router::new(config.routes.clone()).with_overrides(overrides);
+        let mut handles = vec![];
+        for (name, spawn) in self.consumers.iter() {
+            if config.sections.contains_key(*name) {
+                let inbox = router.clone().spawn(outbox, name, config.channel_size)?;
+                handles.extend(spawn(inbox, config)?);
+                info!("Started `{}`", name);
+            }
+        }
+        Ok(handles)
+    }
+
+    fn warn_unknown(&self, config: &Config) {
+        config
+            .sections
+            .keys()
+            .filter(|key| {
+                !self
+                    .casters
+                    .iter()
+                    .map(|(name, _)| name)
+                    .chain(self.consumers.iter().map(|(name, _)| name))
+                    .any(|name| name == key)
+            })
+            .for_each(|key| warn!("Unknown config section `{}`, ignored", key));
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
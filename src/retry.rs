@@ -0,0 +1,162 @@
+use std::{fmt::Display, future::Future, time::Duration};
+
+use color_eyre::{Report, Result};
+use log::warn;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How failed requests are retried, with exponential backoff and jitter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Max number of retries after first attempt, 0 disables retrying
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+
+    /// Delay before first retry, in second
+    #[serde(default = "default_initial_delay")]
+    pub initial_delay: f64,
+
+    /// Max delay between retries, in second
+    #[serde(default = "default_max_delay")]
+    pub max_delay: f64,
+
+    /// Factor by which delay grows after each retry
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+
+    /// Max total time to wait as asked by server (e.g. with 429 responses)
+    /// before giving up, in second
+    #[serde(default = "default_max_retry_after")]
+    pub max_retry_after: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_retries(),
+            initial_delay: default_initial_delay(),
+            max_delay: default_max_delay(),
+            multiplier: default_multiplier(),
+            max_retry_after: default_max_retry_after(),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before retry number `attempt` (starting from 0), randomized
+    /// between half and all of the backoff
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = (self.initial_delay * self.multiplier.powi(attempt as i32))
+            .min(self.max_delay)
+            .max(0.0);
+        Duration::from_secs_f64(backoff * rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Run `f` until it succeeds, fails with a [`Permanent`] error, or runs out
+    /// of retries. Errors with [`RetryAfter`] wait as long as asked, up to
+    /// `max_retry_after` in total.
+    pub async fn retry<T, F, Fut>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        let mut waited = Duration::ZERO;
+        let max_wait = Duration::from_secs_f64(self.max_retry_after.max(0.0));
+        loop {
+            let e = match f().await {
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+            // Asked by server, doesn't count as a retry
+            let delay = match retry_after_of(&e) {
+                Some(delay) if !is_permanent(&e) && waited + delay > max_wait => {
+                    return Err(e.wrap_err(format!(
+                        "Still rate limited after waiting {:.1}s",
+                        waited.as_secs_f64()
+                    )))
+                }
+                Some(delay) if !is_permanent(&e) => {
+                    waited += delay;
+                    warn!(
+                        "{} (Retrying in {:.1}s as requested)",
+                        e,
+                        delay.as_secs_f64()
+                    );
+                    delay
+                }
+                _ if attempt >= self.max_retries || is_permanent(&e) => return Err(e),
+                _ => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "{} (Retrying in {:.1}s, {} retries left)",
+                        e,
+                        delay.as_secs_f64(),
+                        self.max_retries - attempt
+                    );
+                    attempt += 1;
+                    delay
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Marks an error as permanent, so it won't be retried. Attach it with
+/// [`permanent`].
+#[derive(Debug)]
+pub struct Permanent;
+
+impl Display for Permanent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Permanent failure")
+    }
+}
+
+pub fn permanent(e: Report) -> Report {
+    e.wrap_err(Permanent)
+}
+
+pub fn is_permanent(e: &Report) -> bool {
+    e.downcast_ref::<Permanent>().is_some()
+}
+
+/// Marks an error as asking to retry after a delay, e.g. from a 429 response.
+/// Attach it with [`retry_after`].
+#[derive(Debug)]
+pub struct RetryAfter(pub Duration);
+
+impl Display for RetryAfter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limited for {}s", self.0.as_secs_f64())
+    }
+}
+
+pub fn retry_after(e: Report, delay: Duration) -> Report {
+    e.wrap_err(RetryAfter(delay))
+}
+
+pub fn retry_after_of(e: &Report) -> Option<Duration> {
+    e.downcast_ref::<RetryAfter>().map(|x| x.0)
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+fn default_initial_delay() -> f64 {
+    1.0
+}
+
+fn default_max_delay() -> f64 {
+    60.0
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_max_retry_after() -> f64 {
+    60.0 * 10.0
+}
@@ -0,0 +1,319 @@
+use std::fmt::Display;
+
+use color_eyre::Result;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+use crate::{serde_regex, Envelope, Event, EventKind, Inbox, Outbox, Queued};
+
+/// A rule that sends matching events to some consumers. All matchers that are
+/// set must match for the route to apply.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Route {
+    /// Kind of event to match. Value: feed, crates_io, github_release, git.
+    pub kind: Option<EventKind>,
+
+    /// Url of feed to match
+    pub feed: Option<String>,
+
+    /// Name of crate to match
+    #[serde(rename = "crate")]
+    pub crate_name: Option<String>,
+
+    /// Repository to match: `owner/name` of GitHub release, or name (url if
+    /// not named) of git repository
+    pub repo: Option<String>,
+
+    /// Regex to match against title of feed entry, name of crate, name of
+    /// GitHub release, or first line of git commit or name of git tag
+    #[serde(default, with = "serde_regex")]
+    pub title: Option<Regex>,
+
+    /// Regex to match against title and content of feed entry, name and
+    /// version of crate, name, tag and notes of GitHub release, or message
+    /// and name of tag of git event
+    #[serde(default, with = "serde_regex")]
+    pub keyword: Option<Regex>,
+
+    /// Consumers to send to, by name of their config section (e.g.
+    /// `consumer_telegram`). Empty means all consumers.
+    #[serde(default)]
+    pub consumers: Vec<String>,
+
+    /// Targets (e.g. telegram chats) to send to. Empty means targets in
+    /// config of consumer.
+    #[serde(default, alias = "chats")]
+    pub targets: Vec<Target>,
+}
+
+/// Where a consumer should deliver an event to, e.g. a telegram chat
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Target {
+    Id(i64),
+    Name(String),
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Id(id) => write!(f, "{}", id),
+            Target::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Targets of an event routed to a consumer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Targets {
+    /// Targets in config of consumer
+    Default,
+    Only(Vec<Target>),
+}
+
+impl Targets {
+    /// Targets to use, with `default` being targets in config of consumer
+    pub fn resolve<'a>(&'a self, default: &'a [Target]) -> &'a [Target] {
+        match self {
+            Targets::Default => default,
+            Targets::Only(targets) => targets,
+        }
+    }
+}
+
+impl Route {
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(kind) = self.kind {
+            if kind != event.kind() {
+                return false;
+            }
+        }
+
+        match event {
+            Event::Feed {
+                feed,
+                title,
+                content,
+                ..
+            } => {
+                let title = title.as_deref().unwrap_or_default();
+                let content = content.as_deref().unwrap_or_default();
+                self.crate_name.is_none()
+                    && self.repo.is_none()
+                    && self.feed.as_ref().is_none_or(|x| x == feed)
+                    && self.title.as_ref().is_none_or(|x| x.is_match(title))
+                    && self
+                        .keyword
+                        .as_ref()
+                        .is_none_or(|x| x.is_match(title) || x.is_match(content))
+            }
+            Event::CratesIo { name, vers, .. } => {
+                self.feed.is_none()
+                    && self.repo.is_none()
+                    && self.crate_name.as_ref().is_none_or(|x| x == name)
+                    && self.title.as_ref().is_none_or(|x| x.is_match(name))
+                    && self
+                        .keyword
+                        .as_ref()
+                        .is_none_or(|x| x.is_match(name) || x.is_match(vers))
+            }
+            Event::GitHubRelease {
+                repo,
+                tag,
+                name,
+                body,
+                ..
+            } => {
+                let name = name.as_deref().unwrap_or(tag);
+                let body = body.as_deref().unwrap_or_default();
+                self.feed.is_none()
+                    && self.crate_name.is_none()
+                    && self.repo.as_ref().is_none_or(|x| x == repo)
+                    && self.title.as_ref().is_none_or(|x| x.is_match(name))
+                    && self
+                        .keyword
+                        .as_ref()
+                        .is_none_or(|x| x.is_match(name) || x.is_match(tag) || x.is_match(body))
+            }
+            Event::Git {
+                repo, tag, message, ..
+            } => {
+                let title = tag
+                    .as_deref()
+                    .unwrap_or_else(|| message.lines().next().unwrap_or_default());
+                self.feed.is_none()
+                    && self.crate_name.is_none()
+                    && self.repo.as_ref().is_none_or(|x| x == repo)
+                    && self.title.as_ref().is_none_or(|x| x.is_match(title))
+                    && self
+                        .keyword
+                        .as_ref()
+                        .is_none_or(|x| x.is_match(title) || x.is_match(message))
+            }
+        }
+    }
+
+    fn applies_to(&self, consumer: &str) -> bool {
+        self.consumers.is_empty() || self.consumers.iter().any(|x| x == consumer)
+    }
+}
+
+/// Routing table between casters and consumers. Without any route, every
+/// event is sent to every consumer.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// Routes whose targets take place of targets in config of consumers,
+    /// e.g. telegram chats of feeds
+    overrides: Vec<Route>,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self {
+            routes,
+            overrides: vec![],
+        }
+    }
+
+    /// Send events matching `overrides` to their targets, in place of targets
+    /// in config of consumers. They don't decide whether events are sent.
+    pub fn with_overrides(mut self, overrides: Vec<Route>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Decide whether `event` should be sent to `consumer`, and to which
+    /// targets. Targets of all matching routes are merged.
+    pub fn route(&self, event: &Event, consumer: &str) -> Option<Targets> {
+        let res = if self.routes.is_empty() {
+            Some(Targets::Default)
+        } else {
+            match_routes(&self.routes, event, consumer)
+        };
+        match res {
+            Some(Targets::Default) => {
+                Some(match_routes(&self.overrides, event, consumer).unwrap_or(Targets::Default))
+            }
+            res => res,
+        }
+    }
+
+    /// Forward events in `outbox` that are routed to `consumer` into the
+    /// returned [`Inbox`], starting from those pending since last run. Events
+    /// not routed to `consumer` are acknowledged right away.
+    pub fn spawn(self, outbox: &Outbox, consumer: &'static str, size: usize) -> Result<Inbox> {
+        let mut rx = outbox.subscribe();
+        let store = outbox.store().clone();
+        let pending = store.pending(consumer)?;
+        let (tx, inbox) = mpsc::channel(size);
+
+        if !pending.is_empty() {
+            info!("Redelivering {} events to `{}`", pending.len(), consumer)
+        }
+
+        tokio::spawn(async move {
+            let mut last = pending.last().map_or(0, |x| x.id);
+            let forward = |mut queued: Queued| {
+                let targets = match queued.targets.take() {
+                    Some(targets) => Some(Targets::Only(targets)),
+                    None => self.route(&queued.event, consumer),
+                };
+                let tx = &tx;
+                let store = &store;
+                async move {
+                    match targets {
+                        Some(targets) => tx
+                            .send(Envelope::new(queued, targets, consumer, store.clone()))
+                            .await
+                            .is_ok(),
+                        None => {
+                            debug!("{} is not routed to `{}`", queued.event, consumer);
+                            if let Err(e) = store.ack(consumer, queued.id) {
+                                warn!("Failed to acknowledge event #{}: {}", queued.id, e)
+                            }
+                            true
+                        }
+                    }
+                }
+            };
+
+            for queued in pending {
+                if !forward(queued).await {
+                    return;
+                }
+            }
+
+            loop {
+                let queued = match rx.recv().await {
+                    Ok(queued) => queued,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(missed)) => {
+                        // Missed events are still pending in outbox, catch up from there
+                        match store.record_missed(consumer, missed) {
+                            Ok(total) => warn!(
+                                "`{}` lagged behind by {} events ({} in total), replaying from \
+                                 outbox",
+                                consumer, missed, total
+                            ),
+                            Err(e) => warn!("Failed to record missed events: {}", e),
+                        }
+                        let pending = match store.pending(consumer) {
+                            Ok(pending) => pending,
+                            Err(e) => {
+                                error!("Failed to read missed events of `{}`: {}", consumer, e);
+                                continue;
+                            }
+                        };
+                        let missed_from = last;
+                        for queued in pending.into_iter().filter(|x| x.id > missed_from) {
+                            last = queued.id;
+                            if !forward(queued).await {
+                                return;
+                            }
+                        }
+                        continue;
+                    }
+                };
+                // Already delivered
+                if queued.id <= last {
+                    continue;
+                }
+                last = queued.id;
+                if !forward(queued).await {
+                    break;
+                }
+            }
+        });
+
+        Ok(inbox)
+    }
+}
+
+/// Targets of `routes` that match `event` and apply to `consumer` merged, or
+/// `None` if none does
+fn match_routes(routes: &[Route], event: &Event, consumer: &str) -> Option<Targets> {
+    let mut res: Option<Targets> = None;
+
+    for route in routes
+        .iter()
+        .filter(|route| route.applies_to(consumer) && route.matches(event))
+    {
+        res = match (res, route.targets.is_empty()) {
+            (_, true) | (Some(Targets::Default), _) => Some(Targets::Default),
+            (None, false) => Some(Targets::Only(route.targets.clone())),
+            (Some(Targets::Only(mut targets)), false) => {
+                for target in route.targets.iter() {
+                    if !targets.contains(target) {
+                        targets.push(target.clone())
+                    }
+                }
+                Some(Targets::Only(targets))
+            }
+        }
+    }
+
+    res
+}
@@ -0,0 +1,20 @@
+use color_eyre::{eyre::Context, Result};
+use once_cell::sync::OnceCell;
+use sled::Db;
+
+static DB: OnceCell<Db> = OnceCell::new();
+
+/// Open the sled database at `path`. Following calls return the database
+/// opened first, regardless of `path`.
+pub fn open_db<'a>(path: &str) -> Result<&'a Db> {
+    DB.get_or_try_init(|| sled::open(path).wrap_err("Failed to open db"))
+}
+
+/// Get the database opened by [`open_db`].
+///
+/// # Panics
+///
+/// Panics if database is not opened yet.
+pub fn get_db<'a>() -> &'a Db {
+    DB.get().expect("DB not initialized")
+}
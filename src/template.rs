@@ -0,0 +1,142 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{
+    eyre::{eyre, Context as _},
+    Result,
+};
+use serde::Serialize;
+use serde_json::Value;
+use tera::{Context, Tera};
+
+use crate::{truncate_chars, Event, EventKind, EventRecord};
+
+/// User defined templates of a consumer, by kind of event
+pub type TemplateConfig = BTreeMap<EventKind, String>;
+
+/// Renders events into messages with [Tera](https://tera.netlify.app/docs/)
+/// templates.
+///
+/// Fields of the event are available as variables, as in [`EventRecord`],
+/// along with variables given by the consumer. Besides builtin filters of
+/// Tera, these are available:
+///
+/// - `html2text(width=200)`: Convert HTML to plain text
+/// - `escape_html`: Escape `<`, `>`, `&`, `"` and `'`
+/// - `escape_mrkdwn`: Escape `<`, `>` and `&` for Slack
+/// - `truncate_chars(length)`: Cut to at most `length` characters, ending with
+///   `…` if cut
+/// - `timeago`: Format a unix timestamp relative to now, e.g. `3 hours ago`
+pub struct Templates {
+    tera: Tera,
+}
+
+impl Templates {
+    /// Templates from `config`, falling back to `defaults` for kinds of event
+    /// not configured
+    pub fn new(defaults: &[(EventKind, &str)], config: &TemplateConfig) -> Result<Self> {
+        let mut tera = Tera::default();
+        tera.register_filter("html2text", html2text);
+        tera.register_filter("escape_html", escape_html);
+        tera.register_filter("escape_mrkdwn", escape_mrkdwn_filter);
+        tera.register_filter("truncate_chars", truncate_chars_filter);
+        tera.register_filter("timeago", timeago);
+
+        for (kind, template) in defaults {
+            tera.add_raw_template(kind_name(*kind), template)
+                .wrap_err_with(|| format!("Invalid default template of {}", kind_name(*kind)))?;
+        }
+        for (kind, template) in config {
+            tera.add_raw_template(kind_name(*kind), template)
+                .wrap_err_with(|| format!("Invalid template of {}", kind_name(*kind)))?;
+        }
+
+        Ok(Self { tera })
+    }
+
+    /// Render `event` with template of its kind. Extra variables can be given
+    /// in `vars`.
+    pub fn render(&self, event: &Event, vars: impl Serialize) -> Result<String> {
+        let name = kind_name(event.kind());
+        let mut context = event_context(event)?;
+        context.extend(Context::from_serialize(vars).wrap_err("Invalid template variables")?);
+
+        self.tera
+            .render(name, &context)
+            .map_err(|e| eyre!("{:?}", e))
+            .wrap_err_with(|| format!("Failed to render template of {}", name))
+    }
+}
+
+fn kind_name(kind: EventKind) -> &'static str {
+    match kind {
+        EventKind::Feed => "feed",
+        EventKind::CratesIo => "crates_io",
+        EventKind::GitHubRelease => "github_release",
+        EventKind::Git => "git",
+    }
+}
+
+fn event_context(event: &Event) -> Result<Context> {
+    Context::from_serialize(EventRecord(event.clone())).wrap_err("Invalid event")
+}
+
+fn html2text(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let html = tera::try_get_value!("html2text", "value", String, value);
+    let width = match args.get("width") {
+        Some(width) => tera::try_get_value!("html2text", "width", usize, width),
+        None => 200,
+    };
+    Ok(html2text::from_read(html.as_bytes(), width).into())
+}
+
+fn escape_html(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = tera::try_get_value!("escape_html", "value", String, value);
+    Ok(html_escape::encode_safe(&text).into_owned().into())
+}
+
+fn escape_mrkdwn_filter(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = tera::try_get_value!("escape_mrkdwn", "value", String, value);
+    Ok(escape_mrkdwn(&text).into())
+}
+
+fn truncate_chars_filter(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let text = tera::try_get_value!("truncate_chars", "value", String, value);
+    let length = match args.get("length") {
+        Some(length) => tera::try_get_value!("truncate_chars", "length", usize, length),
+        None => return Err("Filter `truncate_chars` expected an arg called `length`".into()),
+    };
+    Ok(truncate_chars(&text, length).into())
+}
+
+/// Escape control characters of Slack mrkdwn
+pub fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn timeago(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let time = tera::try_get_value!("timeago", "value", i64, value);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let secs = now - time;
+
+    let (n, unit) = match secs.abs() {
+        x if x < 60 => return Ok("just now".into()),
+        x if x < 60 * 60 => (x / 60, "minute"),
+        x if x < 24 * 60 * 60 => (x / 60 / 60, "hour"),
+        x => (x / 24 / 60 / 60, "day"),
+    };
+    let plural = if n == 1 { "" } else { "s" };
+    Ok(if secs < 0 {
+        format!("in {} {}{}", n, unit, plural)
+    } else {
+        format!("{} {}{} ago", n, unit, plural)
+    }
+    .into())
+}
@@ -1,5 +1,168 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
 const TEXT: &str = "&lt;p&gt;&lt;strong&gt;t;&gt; - &lt;/p&gt;";
 
+/// Open a fresh database for this test process
+fn test_db() -> &'static sled::Db {
+    let path = std::env::temp_dir().join(format!("caster-test-{}", std::process::id()));
+    crate::open_db(path.to_str().unwrap()).unwrap()
+}
+
+/// A minimal HTTP server standing in for remote APIs. Every request is
+/// recorded and answered with the raw response returned by `respond`.
+async fn mock_server(
+    respond: impl Fn(&str) -> String + Send + Sync + 'static,
+) -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = requests.clone();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = vec![];
+            let mut chunk = [0; 4096];
+            let req = loop {
+                let n = stream.read(&mut chunk).await.unwrap_or_default();
+                buf.extend_from_slice(&chunk[..n]);
+                let req = String::from_utf8_lossy(&buf).into_owned();
+                let Some(pos) = req.find("\r\n\r\n") else {
+                    // Closed before headers are complete
+                    if n == 0 {
+                        break None;
+                    }
+                    continue;
+                };
+                let len = req
+                    .lines()
+                    .find_map(|x| {
+                        let (key, value) = x.split_once(':')?;
+                        key.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or_default();
+                if n == 0 || buf.len() >= pos + 4 + len {
+                    break Some(req);
+                }
+            };
+            let Some(req) = req else {
+                continue;
+            };
+            let res = respond(&req);
+            recorded.lock().unwrap().push(req);
+            stream.write_all(res.as_bytes()).await.unwrap();
+        }
+    });
+
+    (addr, requests)
+}
+
+/// A minimal SMTP server accepting every email. Recipients and data of each
+/// email are recorded.
+async fn smtp_sink() -> (u16, Arc<Mutex<Vec<(Vec<String>, String)>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let emails = Arc::new(Mutex::new(vec![]));
+    let recorded = emails.clone();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let recorded = recorded.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                let mut recipients = vec![];
+                write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply: &[u8] = match &line.to_uppercase() {
+                        x if x.starts_with("RCPT TO:") => {
+                            recipients.push(line[8..].trim_matches(['<', '>', ' ']).to_owned());
+                            b"250 OK\r\n"
+                        }
+                        x if x == "DATA" => {
+                            write.write_all(b"354 Go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                data += &line;
+                                data += "\n";
+                            }
+                            recorded
+                                .lock()
+                                .unwrap()
+                                .push((std::mem::take(&mut recipients), data));
+                            b"250 OK\r\n"
+                        }
+                        x if x == "QUIT" => {
+                            write.write_all(b"221 Bye\r\n").await.unwrap();
+                            break;
+                        }
+                        _ => b"250 localhost\r\n",
+                    };
+                    write.write_all(reply).await.unwrap();
+                }
+            });
+        }
+    });
+
+    (port, emails)
+}
+
+/// Build a raw HTTP response
+fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let headers = headers
+        .iter()
+        .map(|(key, value)| format!("{}: {}\r\n", key, value))
+        .collect::<String>();
+    format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    )
+}
+
+/// Start consumer `name` with `run` on a fresh outbox routed by `router`
+fn start_consumer(
+    name: &'static str,
+    router: crate::Router,
+    run: impl FnOnce(crate::Inbox) -> tokio::task::JoinHandle<()>,
+) -> crate::Outbox {
+    let tree = test_db().open_tree(format!("{}-test", name)).unwrap();
+    let outbox = crate::Outbox::open(tree, vec![name], 16).unwrap();
+    run(router.spawn(&outbox, name, 16).unwrap());
+    outbox
+}
+
+/// Send `events` to `outbox` and wait until consumer `name` acknowledges all
+/// of them, returning their ids
+async fn deliver(outbox: &crate::Outbox, name: &str, events: Vec<crate::Event>) -> Vec<u64> {
+    use std::time::{Duration, Instant};
+
+    let ids = events
+        .into_iter()
+        .map(|x| outbox.send(x).unwrap())
+        .collect();
+    let start = Instant::now();
+    while !outbox.store().pending(name).unwrap().is_empty() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "Events are not delivered to `{}`",
+            name
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    ids
+}
+
 #[test]
 fn html2text() {
     use html2text::from_read;
@@ -26,6 +189,7 @@ fn feed() {
 }
 
 #[test]
+#[allow(clippy::get_first)]
 fn escape() {
     let content = std::fs::read("data/miao.xml").unwrap();
     let feed = feed_rs::parser::parse(&content[..]).unwrap();
@@ -34,3 +198,2385 @@ fn escape() {
     let encoded = html_escape::encode_safe(&summary.content);
     println!("{}", encoded)
 }
+
+#[test]
+fn example_config() {
+    use crate::{Config, FeedConfig, TelegramConfig, TelegramConsumer, Templates};
+
+    let config = Config::with_path(Some("Caster.example.toml")).unwrap();
+    let feed: FeedConfig = config.section("caster_feed").unwrap().unwrap();
+    assert_eq!(feed.sources().len(), 2);
+    assert_eq!(config.routes.len(), 2);
+    let telegram: TelegramConfig = config.section("consumer_telegram").unwrap().unwrap();
+    Templates::new(TelegramConsumer::TEMPLATES, &telegram.templates).unwrap();
+}
+
+#[test]
+fn config_sections() {
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    use crate::{Caster, Config, FeedCaster, FeedConfig, Target};
+
+    let config: Config = Figment::new()
+        .merge(Toml::string(
+            r#"
+            [caster_feed]
+            interval = 30.0
+            urls = [
+                "http://localhost/feed.xml",
+                { url = "http://localhost/atom.xml", interval = 5.0, chats = [ -100 ] },
+            ]
+            "#,
+        ))
+        .extract()
+        .unwrap();
+
+    let feed: FeedConfig = config.section(FeedCaster::NAME).unwrap().unwrap();
+    let sources = feed.sources();
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources[0].url, "http://localhost/feed.xml");
+    assert_eq!(sources[0].interval, Some(30.0));
+    assert_eq!(sources[1].url, "http://localhost/atom.xml");
+    assert_eq!(sources[1].interval, Some(5.0));
+    assert_eq!(sources[1].chats, [Target::Id(-100)]);
+    assert!(config
+        .section::<FeedConfig>("caster_crates")
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn pipeline() {
+    use std::sync::{Arc, Mutex};
+
+    use figment::{
+        providers::{Format, Serialized, Toml},
+        Figment,
+    };
+    use tokio::task::JoinHandle;
+
+    use crate::{run, Caster, Config, Consumer, Event, Inbox, Outbox, Registry};
+
+    static RECEIVED: Mutex<Vec<Event>> = Mutex::new(vec![]);
+
+    fn event() -> Event {
+        Event::CratesIo {
+            name: "caster".to_owned(),
+            vers: "0.1.0".to_owned(),
+            links: None,
+            yanked: false,
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Empty {}
+
+    struct TestCaster;
+
+    impl Caster for TestCaster {
+        type Config = Empty;
+
+        const NAME: &'static str = "caster_test";
+
+        fn run(tx: Outbox, _: Empty) -> JoinHandle<()> {
+            tokio::spawn(async move {
+                tx.send(event()).unwrap();
+            })
+        }
+    }
+
+    struct TestConsumer;
+
+    impl Consumer for TestConsumer {
+        type Config = Empty;
+
+        const NAME: &'static str = "consumer_test";
+
+        fn run(mut inbox: Inbox, _: Empty) -> JoinHandle<()> {
+            tokio::spawn(async move {
+                if let Some(envelope) = inbox.recv().await {
+                    envelope.ack().unwrap();
+                    RECEIVED.lock().unwrap().push(envelope.event)
+                }
+            })
+        }
+    }
+
+    let db_path = std::env::temp_dir().join(format!("caster-test-{}", std::process::id()));
+    let config: Config = Figment::new()
+        .merge(Serialized::default("db_path", db_path))
+        .merge(Toml::string("[caster_test]\n[consumer_test]"))
+        .extract()
+        .unwrap();
+
+    let registry = Registry::empty()
+        .caster::<TestCaster>()
+        .consumer::<TestConsumer>();
+
+    run(Arc::new(config), registry).await.unwrap();
+
+    assert_eq!(*RECEIVED.lock().unwrap(), [event()]);
+}
+
+#[test]
+fn routing() {
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+
+    use crate::{Event, FeedConfig, Route, Router, Target, Targets};
+
+    #[derive(serde::Deserialize)]
+    struct Routes {
+        routes: Vec<Route>,
+    }
+
+    let Routes { routes } = Figment::new()
+        .merge(Toml::string(
+            r#"
+            [[routes]]
+            kind = "crates_io"
+            consumers = [ "consumer_telegram" ]
+            chats = [ -100 ]
+
+            [[routes]]
+            crate = "tokio"
+            chats = [ "@tokio" ]
+
+            [[routes]]
+            feed = "http://localhost/feed.xml"
+            keyword = "(?i)rust"
+            "#,
+        ))
+        .extract()
+        .unwrap();
+    let router = Router::new(routes);
+
+    let krate = |name: &str| Event::CratesIo {
+        name: name.to_owned(),
+        vers: "1.0.0".to_owned(),
+        links: None,
+        yanked: false,
+    };
+    let feed = |content: &str| Event::Feed {
+        feed: "http://localhost/feed.xml".to_owned(),
+        name: None,
+        entry_id: "FEED-0-0".to_owned(),
+        time: 0,
+        content: Some(content.to_owned()),
+        title: None,
+        link: None,
+    };
+
+    assert_eq!(
+        router.route(&krate("serde"), "consumer_telegram"),
+        Some(Targets::Only(vec![Target::Id(-100)]))
+    );
+    assert_eq!(router.route(&krate("serde"), "consumer_other"), None);
+    assert_eq!(
+        router.route(&krate("tokio"), "consumer_telegram"),
+        Some(Targets::Only(vec![
+            Target::Id(-100),
+            Target::Name("@tokio".to_owned())
+        ]))
+    );
+    assert_eq!(
+        router.route(&feed("Rust 2021 is out"), "consumer_other"),
+        Some(Targets::Default)
+    );
+    assert_eq!(
+        router.route(&feed("Go 1.18 is out"), "consumer_other"),
+        None
+    );
+
+    let feeds = Figment::new()
+        .merge(Toml::string(
+            r#"
+            [[feeds]]
+            url = "http://localhost/feed.xml"
+            chats = [ -200 ]
+            "#,
+        ))
+        .extract::<FeedConfig>()
+        .unwrap();
+    let router = router.with_overrides(feeds.routes());
+    let event = feed("Rust 2021 is out");
+    assert_eq!(
+        router.route(&event, "consumer_telegram"),
+        Some(Targets::Only(vec![Target::Id(-200)]))
+    );
+    // Chats of feed are telegram chats, not targets of other consumers
+    assert_eq!(
+        router.route(&event, "consumer_other"),
+        Some(Targets::Default)
+    );
+    // and don't send entries not routed otherwise
+    assert_eq!(
+        router.route(&feed("Go 1.18 is out"), "consumer_telegram"),
+        None
+    );
+    assert_eq!(
+        Router::default().route(&feed("Go 1.18 is out"), "consumer_other"),
+        Some(Targets::Default)
+    );
+}
+
+#[tokio::test]
+async fn conditional_request() {
+    use std::time::Duration;
+
+    use crate::{FeedCaster, FeedConfig, FeedItem, FeedSource, Outbox};
+
+    let feed = std::fs::read_to_string("data/miao.xml").unwrap();
+    let (addr, requests) = mock_server(move |req| {
+        if req.contains("if-none-match: \"miao\"") {
+            response("304 Not Modified", &[], "")
+        } else {
+            response("200 OK", &[("ETag", "\"miao\"")], &feed)
+        }
+    })
+    .await;
+
+    let mut source = FeedSource::new(format!("{}/miao.xml", addr));
+    source.interval = Some(0.1);
+    source.ignore_days = Some(u64::MAX / (60 * 60 * 24));
+    let config = FeedConfig {
+        feeds: vec![FeedItem::Source(Box::new(source))],
+        ignore_days: 0,
+        interval: 0.1,
+        min_interval: None,
+        max_interval: 0.1,
+    };
+
+    let outbox = Outbox::open(
+        test_db().open_tree("outbox-conditional").unwrap(),
+        vec!["consumer_test"],
+        16,
+    )
+    .unwrap();
+    let handle = <FeedCaster as crate::Caster>::run(outbox.clone(), config);
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    handle.abort();
+
+    let requests = requests.lock().unwrap();
+    assert!(requests.len() >= 2);
+    assert!(!requests[0].contains("if-none-match"));
+    assert!(requests[1].contains("if-none-match: \"miao\""));
+
+    assert_eq!(outbox.store().pending("consumer_test").unwrap().len(), 2);
+}
+
+#[test]
+fn schedule() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use reqwest::header::{HeaderMap, HeaderValue, CACHE_CONTROL, RETRY_AFTER};
+
+    use crate::{Hints, Schedule};
+
+    let secs = Duration::from_secs;
+    // Thursday, 1970-01-01 00:00 UTC
+    let now = UNIX_EPOCH;
+    let none = Hints::default();
+
+    let mut schedule = Schedule::new(secs(60), secs(30), secs(600));
+    assert_eq!(schedule.next(false, &none, now), secs(90));
+    assert_eq!(schedule.next(false, &none, now), secs(135));
+    assert_eq!(
+        schedule.next(true, &none, now),
+        Duration::from_millis(67500)
+    );
+    assert_eq!(
+        schedule.next(true, &none, now),
+        secs(33) + Duration::from_millis(750)
+    );
+    assert_eq!(schedule.next(true, &none, now), secs(30));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("public, max-age=300"),
+    );
+    headers.insert(RETRY_AFTER, HeaderValue::from_static("1200"));
+    let hints = Hints::from_headers(&headers);
+    assert_eq!(hints.max_age, Some(secs(300)));
+    assert_eq!(hints.retry_after, Some(secs(1200)));
+    assert_eq!(schedule.next(true, &hints, now), secs(1200));
+
+    let body = br#"<rss><channel><ttl>60</ttl>
+        <skipHours><hour>0</hour><hour>1</hour></skipHours>
+        <skipDays><day>Friday</day></skipDays>
+        </channel></rss>"#;
+    let hints = Hints::default().with_body(Some(60), body);
+    assert_eq!(hints.skip_hours, [0, 1]);
+    assert_eq!(hints.skip_days, [4]);
+    // TTL is capped by max interval, and 00:10 falls in skipped hours
+    assert_eq!(schedule.next(true, &hints, now), secs(2 * 60 * 60));
+
+    let mut schedule = Schedule::new(secs(60), secs(60), secs(60));
+    let at = now + secs(23 * 60 * 60);
+    assert_eq!(schedule.next(false, &hints, at), secs(60));
+    // Friday is skipped, so are 00:00 to 02:00 of Saturday
+    assert_eq!(
+        schedule.next(false, &hints, at + secs(59 * 60 + 30)),
+        secs(26 * 60 * 60 + 30)
+    );
+}
+
+#[tokio::test]
+async fn outbox() {
+    use crate::{Event, Outbox, Router, Targets};
+
+    let tree = test_db().open_tree("outbox-test").unwrap();
+    let event = |name: &str| Event::CratesIo {
+        name: name.to_owned(),
+        vers: "1.0.0".to_owned(),
+        links: None,
+        yanked: false,
+    };
+
+    let outbox = Outbox::open(tree.clone(), vec!["consumer_a", "consumer_b"], 16).unwrap();
+    let first = outbox.send(event("foo")).unwrap();
+    let second = outbox.send(event("bar")).unwrap();
+    outbox.store().ack("consumer_a", first).unwrap();
+
+    // Reopen as if restarted
+    let outbox = Outbox::open(tree.clone(), vec!["consumer_a", "consumer_b"], 16).unwrap();
+    let store = outbox.store();
+    let ids = |consumer| {
+        store
+            .pending(consumer)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids("consumer_a"), [second]);
+    assert_eq!(ids("consumer_b"), [first, second]);
+
+    store.ack("consumer_b", first).unwrap();
+    assert_eq!(store.get(first).unwrap(), None);
+
+    // Pending events are redelivered before new ones
+    let mut inbox = Router::default().spawn(&outbox, "consumer_b", 16).unwrap();
+    let third = outbox.send(event("baz")).unwrap();
+    for (id, name) in [(second, "bar"), (third, "baz")] {
+        let envelope = inbox.recv().await.unwrap();
+        assert_eq!(envelope.id, id);
+        assert_eq!(envelope.event, event(name));
+        assert_eq!(envelope.targets, Targets::Default);
+        envelope.ack().unwrap();
+    }
+    assert!(ids("consumer_b").is_empty());
+
+    // Pending marks of removed consumers are dropped
+    let outbox = Outbox::open(tree, vec!["consumer_b"], 16).unwrap();
+    assert_eq!(outbox.store().get(second).unwrap(), None);
+    assert_eq!(outbox.store().get(third).unwrap(), None);
+
+    // Events sent at once from casters are broadcast in order of ids
+    let tree = test_db().open_tree("outbox-order-test").unwrap();
+    let outbox = Outbox::open(tree, vec!["consumer_b"], 128).unwrap();
+    let mut rx = outbox.subscribe();
+    let senders = (0..2)
+        .map(|_| {
+            let outbox = outbox.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    outbox.send(event("foo")).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    senders.into_iter().for_each(|x| x.join().unwrap());
+    let mut last = 0;
+    for _ in 0..100 {
+        let id = rx.recv().await.unwrap().id;
+        assert!(id > last);
+        last = id;
+    }
+}
+
+#[tokio::test]
+async fn retry() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use color_eyre::eyre::eyre;
+
+    use crate::{parse_retry_after, permanent, retry_after, RetryConfig};
+
+    let secs = std::time::Duration::from_secs_f64;
+
+    let config = RetryConfig {
+        max_retries: 3,
+        initial_delay: 0.01,
+        max_delay: 0.02,
+        multiplier: 2.0,
+        max_retry_after: 0.05,
+    };
+    let delay = config.delay(0).as_secs_f64();
+    assert!((0.005..=0.01).contains(&delay));
+    let delay = config.delay(5).as_secs_f64();
+    assert!((0.01..=0.02).contains(&delay));
+
+    let attempts = AtomicU32::new(0);
+    let res = config
+        .retry(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(eyre!("Temporary failure")),
+                n => Ok(n),
+            }
+        })
+        .await;
+    assert_eq!(res.unwrap(), 2);
+
+    let attempts = AtomicU32::new(0);
+    let res: color_eyre::Result<()> = config
+        .retry(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(eyre!("Temporary failure"))
+        })
+        .await;
+    assert!(res.is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+    let attempts = AtomicU32::new(0);
+    let res: color_eyre::Result<()> = config
+        .retry(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(permanent(eyre!("Bad request")))
+        })
+        .await;
+    assert!(res.is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+    // Waiting as asked by server doesn't use up retries
+    let config = RetryConfig {
+        max_retries: 0,
+        ..config
+    };
+    let attempts = AtomicU32::new(0);
+    let start = std::time::Instant::now();
+    let res = config
+        .retry(|| async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(retry_after(eyre!("Too many requests"), secs(0.02))),
+                n => Ok(n),
+            }
+        })
+        .await;
+    assert_eq!(res.unwrap(), 2);
+    assert!(start.elapsed() >= secs(0.04));
+
+    // But only up to `max_retry_after` in total
+    let attempts = AtomicU32::new(0);
+    let res: color_eyre::Result<()> = config
+        .retry(|| async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(retry_after(eyre!("Too many requests"), secs(0.02)))
+        })
+        .await;
+    assert!(res.is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+    assert_eq!(
+        parse_retry_after(
+            r#"{"ok":false,"error_code":429,"description":"Too Many Requests: retry after 7","parameters":{"retry_after":7}}"#
+        ),
+        Some(secs(7.0))
+    );
+    assert_eq!(parse_retry_after("Bad Gateway"), None);
+}
+
+#[tokio::test]
+async fn rate_limit() {
+    use std::time::{Duration, Instant};
+
+    use crate::RateLimiter;
+
+    let ms = Duration::from_millis;
+
+    let limiter = RateLimiter::new(ms(20), 2);
+    let start = Instant::now();
+    for _ in 0..2 {
+        limiter.wait().await;
+    }
+    assert!(start.elapsed() < ms(20));
+    for _ in 0..3 {
+        limiter.wait().await;
+    }
+    assert!(start.elapsed() >= ms(60));
+
+    let limiter = RateLimiter::per(100, Duration::from_secs(1));
+    limiter.pause(ms(30));
+    let start = Instant::now();
+    limiter.wait().await;
+    assert!(start.elapsed() >= ms(25));
+}
+
+#[test]
+fn dead_letters() {
+    use crate::{DeadLetters, Event, Outbox, Target};
+
+    let db = test_db();
+    let event = Event::CratesIo {
+        name: "caster".to_owned(),
+        vers: "0.1.0".to_owned(),
+        links: None,
+        yanked: false,
+    };
+
+    let dead_letters = DeadLetters::open(db).unwrap();
+    let id = dead_letters
+        .push(
+            "consumer_telegram",
+            event.clone(),
+            vec![Target::Id(-100)],
+            "Bad gateway".to_owned(),
+        )
+        .unwrap();
+    let letter = dead_letters
+        .list()
+        .unwrap()
+        .into_iter()
+        .find(|x| x.id == id)
+        .unwrap();
+    assert_eq!(letter.consumer, "consumer_telegram");
+    assert_eq!(letter.event, event);
+
+    let outbox = Outbox::open(
+        db.open_tree("outbox-dead-letters").unwrap(),
+        vec!["consumer_telegram"],
+        16,
+    )
+    .unwrap();
+    dead_letters.replay(id, outbox.store()).unwrap();
+    assert!(dead_letters.list().unwrap().iter().all(|x| x.id != id));
+
+    let pending = outbox.store().pending("consumer_telegram").unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].event, event);
+    assert_eq!(pending[0].targets, Some(vec![Target::Id(-100)]));
+}
+
+#[tokio::test]
+async fn lagged_consumer() {
+    use crate::{Event, Outbox, Router};
+
+    let tree = test_db().open_tree("lagged-test").unwrap();
+    let event = |vers: usize| Event::CratesIo {
+        name: "foo".to_owned(),
+        vers: format!("1.0.{}", vers),
+        links: None,
+        yanked: false,
+    };
+
+    let outbox = Outbox::open(tree, vec!["consumer_a"], 2).unwrap();
+    let mut inbox = Router::default().spawn(&outbox, "consumer_a", 1).unwrap();
+
+    // More events than the broadcast channel holds before consumer catches up
+    for i in 0..10 {
+        outbox.send(event(i)).unwrap();
+    }
+    for i in 0..10 {
+        let envelope = inbox.recv().await.unwrap();
+        assert_eq!(envelope.event, event(i));
+        envelope.ack().unwrap();
+    }
+    assert!(outbox.store().pending("consumer_a").unwrap().is_empty());
+    assert_eq!(outbox.store().missed("consumer_a").unwrap(), 8);
+
+    // Nothing is delivered twice
+    outbox.send(event(10)).unwrap();
+    assert_eq!(inbox.recv().await.unwrap().event, event(10));
+}
+
+#[test]
+fn split_html() {
+    use crate::split_html;
+
+    assert_eq!(split_html("<b>short</b>", 4096), ["<b>short</b>"]);
+
+    // Paragraphs are kept together when possible
+    let html = "<b>Title</b>\n\nfirst paragraph\n\nsecond paragraph";
+    assert_eq!(
+        split_html(html, 36),
+        ["<b>Title</b>\n\nfirst paragraph", "second paragraph"]
+    );
+
+    // Tags are closed and reopened across parts
+    let html = "<b><a href=\"x\">aaaa bbbb cccc</a> dddd</b>";
+    let parts = split_html(html, 30);
+    assert_eq!(
+        parts,
+        [
+            "<b><a href=\"x\">aaaa</a></b>",
+            "<b><a href=\"x\">bbbb</a></b>",
+            "<b><a href=\"x\">cccc</a></b>",
+            "<b>dddd</b>"
+        ]
+    );
+    assert!(parts.iter().all(|x| x.chars().count() <= 30));
+
+    // Entities are not broken, long words are
+    let parts = split_html("&amp;&amp;&amp;xxxxxxxxxx", 8);
+    assert!(parts.iter().all(|x| x.chars().count() <= 8));
+    assert_eq!(parts[0], "&amp;");
+    assert_eq!(parts.concat(), "&amp;&amp;&amp;xxxxxxxxxx");
+
+    // Tags longer than a part are split anyway
+    let html = format!(
+        "ab<a href=\"https://example.com/{}\">link</a>",
+        "x".repeat(40)
+    );
+    let parts = split_html(&html, 20);
+    assert!(parts.iter().all(|x| x.chars().count() <= 20));
+    assert_eq!(parts[0], "ab");
+    assert_eq!(parts.concat(), html);
+}
+
+#[test]
+fn templates() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::{
+        DiscordConsumer, Event, EventKind, GotifyConsumer, IrcConsumer, MastodonConsumer,
+        NtfyConsumer, SlackConsumer, TelegramConsumer, TemplateConfig, Templates,
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let feed = |content: &str, link: Option<&str>| Event::Feed {
+        feed: "https://example.com/feed.xml".to_owned(),
+        name: Some("Blog & News".to_owned()),
+        entry_id: "1".to_owned(),
+        time: now - 2 * 60 * 60 - 10,
+        content: Some(content.to_owned()),
+        title: Some("Hello <world>".to_owned()),
+        link: link.map(ToOwned::to_owned),
+    };
+    let crates = Event::CratesIo {
+        name: "foo".to_owned(),
+        vers: "1.0.0".to_owned(),
+        links: None,
+        yanked: true,
+    };
+    let defaults = &[
+        (EventKind::Feed, "{{ title }}"),
+        (
+            EventKind::CratesIo,
+            "{{ name }} {{ vers }}{% if yanked %} (yanked){% endif %}",
+        ),
+    ];
+
+    let mut config = TemplateConfig::new();
+    config.insert(
+        EventKind::Feed,
+        "<a href=\"{{ link }}\">{{ name | escape_html }}</a> {{ content | html2text | \
+         truncate(length=max, end=\"...\") }} ({{ kind }}, {{ time | timeago }})"
+            .to_owned(),
+    );
+    let templates = Templates::new(defaults, &config).unwrap();
+    let vars = serde_json::json!({ "max": 5 });
+
+    assert_eq!(
+        templates
+            .render(&feed("<p>Lorem ipsum</p>", Some("https://x")), &vars)
+            .unwrap(),
+        "<a href=\"https://x\">Blog &amp; News</a> Lorem... (feed, 2 hours ago)"
+    );
+    // Defaults are used for kinds not configured
+    assert_eq!(
+        templates.render(&crates, &vars).unwrap(),
+        "foo 1.0.0 (yanked)"
+    );
+
+    // Invalid templates are rejected early
+    config.insert(EventKind::CratesIo, "{{ name".to_owned());
+    assert!(Templates::new(defaults, &config).is_err());
+
+    // Layouts of telegram
+    let templates = Templates::new(TelegramConsumer::TEMPLATES, &TemplateConfig::new()).unwrap();
+    let vars = |max: usize| serde_json::json!({ "content_max_length": max });
+    assert_eq!(
+        templates
+            .render(&feed("<p>Lorem ipsum</p>", Some("https://x")), vars(0))
+            .unwrap(),
+        "<b>[ <a href=\"https://x\">Blog &amp; News</a> ]  Hello &lt;world&gt;</b>\n\nLorem ipsum"
+    );
+    assert_eq!(
+        templates
+            .render(&feed("Lorem ipsum", None), vars(6))
+            .unwrap(),
+        "<b>[ Blog &amp; News ]  Hello &lt;world&gt;</b>\n\nLorem..."
+    );
+    assert_eq!(
+        templates.render(&crates, vars(0)).unwrap(),
+        "[ <a href=\"https://crates.io/crates/foo\">Crates.io</a> ] New update: \
+         <b>foo</b>\nVersion: 1.0.0\nYanked: true"
+    );
+    let release = Event::GitHubRelease {
+        repo: "foo/bar".to_owned(),
+        tag: "v1.0.0".to_owned(),
+        name: None,
+        body: None,
+        link: "https://github.com/foo/bar/releases/tag/v1.0.0".to_owned(),
+        prerelease: true,
+        time: now,
+    };
+    assert_eq!(
+        templates.render(&release, vars(0)).unwrap(),
+        "[ foo/bar ] New pre-release: <a href=\"https://github.com/foo/bar/releases/tag/v1.0.0\">\
+         <b>v1.0.0</b></a>"
+    );
+    let git = Event::Git {
+        repo: "foo".to_owned(),
+        branch: Some("main".to_owned()),
+        tag: None,
+        commit: "0123456789abcdef".to_owned(),
+        author: "Alice".to_owned(),
+        message: "Fix <bar>".to_owned(),
+        time: now,
+    };
+    assert_eq!(
+        templates.render(&git, vars(0)).unwrap(),
+        "[ foo ] New commit on <b>main</b>\n<code>0123456</code> by Alice\nFix &lt;bar&gt;"
+    );
+
+    // Every consumer has a layout of each kind
+    for defaults in [
+        DiscordConsumer::TEMPLATES,
+        SlackConsumer::TEMPLATES,
+        NtfyConsumer::TEMPLATES,
+        GotifyConsumer::TEMPLATES,
+        IrcConsumer::TEMPLATES,
+        MastodonConsumer::TEMPLATES,
+    ] {
+        let templates = Templates::new(defaults, &TemplateConfig::new()).unwrap();
+        for event in [
+            feed("Lorem ipsum", None),
+            crates.clone(),
+            release.clone(),
+            git.clone(),
+        ] {
+            templates.render(&event, vars(0)).unwrap();
+            templates.render(&event, vars(5)).unwrap();
+        }
+    }
+    let templates = Templates::new(DiscordConsumer::TEMPLATES, &TemplateConfig::new()).unwrap();
+    assert_eq!(
+        templates
+            .render(&feed("Lorem ipsum", None), vars(5))
+            .unwrap(),
+        "Lore…"
+    );
+}
+
+#[tokio::test]
+async fn discord() {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use crate::{run_discord, DiscordConfig, Event, Router, TemplateConfig};
+
+    // Rate limited at first, then bucket runs out after each message
+    let count = AtomicUsize::new(0);
+    let (base, requests) = mock_server(move |_| {
+        if count.fetch_add(1, Ordering::SeqCst) == 0 {
+            response(
+                "429 Too Many Requests",
+                &[("Content-Type", "application/json")],
+                r#"{"message": "You are being rate limited.", "retry_after": 0.05, "global": false}"#,
+            )
+        } else {
+            response(
+                "200 OK",
+                &[("X-RateLimit-Remaining", "0"), ("X-RateLimit-Reset-After", "0.2")],
+                "{}",
+            )
+        }
+    })
+    .await;
+
+    let outbox = start_consumer("consumer_discord", Router::default(), |inbox| {
+        run_discord(
+            inbox,
+            DiscordConfig {
+                webhooks: vec![format!("{}/api/webhooks/1/token", base)],
+                username: Some("caster".to_owned()),
+                avatar_url: None,
+                content_max_length: 0,
+                retry: Default::default(),
+                templates: TemplateConfig::new(),
+            },
+        )
+    });
+
+    let start = Instant::now();
+    deliver(
+        &outbox,
+        "consumer_discord",
+        vec![
+            Event::Feed {
+                feed: "https://example.com/feed.xml".to_owned(),
+                name: Some("Blog".to_owned()),
+                entry_id: "1".to_owned(),
+                time: 1_600_000_000,
+                content: Some("<p>Hello world</p>".to_owned()),
+                title: Some("Hello".to_owned()),
+                link: Some("https://example.com/1".to_owned()),
+            },
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+    // Waited for retry_after and reset of bucket
+    assert!(start.elapsed() >= Duration::from_millis(250));
+
+    // Urls of webhooks are kept out of errors
+    let e = crate::get_client()
+        .post("http://127.0.0.1:1/api/webhooks/1/token")
+        .send()
+        .await
+        .unwrap_err();
+    assert!(!format!("{:#}", crate::hide_url(e)).contains("token"));
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[1].starts_with("POST /api/webhooks/1/token?wait=true "));
+    let body = |req: &str| -> serde_json::Value {
+        serde_json::from_str(&req[req.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    };
+    assert_eq!(body(&requests[0]), body(&requests[1]));
+    assert_eq!(
+        body(&requests[1]),
+        serde_json::json!({
+            "username": "caster",
+            "embeds": [{
+                "title": "Hello",
+                "url": "https://example.com/1",
+                "description": "Hello world",
+                "timestamp": "2020-09-13T12:26:40Z",
+                "author": { "name": "Blog" },
+            }]
+        })
+    );
+    assert_eq!(
+        body(&requests[2])["embeds"][0],
+        serde_json::json!({
+            "title": "foo 1.0.0",
+            "url": "https://crates.io/crates/foo",
+            "description": "New update: **foo** 1.0.0",
+            "footer": { "text": "Crates.io" },
+            "color": 0xe67e22,
+        })
+    );
+}
+
+#[tokio::test]
+async fn slack() {
+    use crate::{run_slack, Event, EventKind, Route, Router, SlackConfig, Target, TemplateConfig};
+
+    let (base, requests) = mock_server(|_| response("200 OK", &[], "ok")).await;
+
+    // Feeds go to first webhook, crates to second one
+    let router = Router::new(vec![
+        Route {
+            kind: Some(EventKind::Feed),
+            consumers: vec!["consumer_slack".to_owned()],
+            targets: vec![Target::Id(0)],
+            ..Route::default()
+        },
+        Route {
+            crate_name: Some("foo".to_owned()),
+            consumers: vec!["consumer_slack".to_owned()],
+            targets: vec![Target::Id(1)],
+            ..Route::default()
+        },
+    ]);
+    let outbox = start_consumer("consumer_slack", router, |inbox| {
+        run_slack(
+            inbox,
+            SlackConfig {
+                webhooks: vec![
+                    format!("{}/services/default", base),
+                    format!("{}/services/crates", base),
+                ],
+                content_max_length: 0,
+                retry: Default::default(),
+                templates: TemplateConfig::new(),
+            },
+        )
+    });
+
+    deliver(
+        &outbox,
+        "consumer_slack",
+        vec![
+            Event::Feed {
+                feed: "https://example.com/feed.xml".to_owned(),
+                name: None,
+                entry_id: "1".to_owned(),
+                time: 1_600_000_000,
+                content: Some("<p>1 &lt; 2</p>".to_owned()),
+                title: Some("Hello".to_owned()),
+                link: Some("https://example.com/1".to_owned()),
+            },
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+
+    let requests = requests.lock().unwrap();
+    let body = |path: &str| -> serde_json::Value {
+        let req = requests
+            .iter()
+            .find(|x| x.starts_with(&format!("POST {} ", path)))
+            .unwrap();
+        serde_json::from_str(&req[req.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    };
+    assert_eq!(requests.len(), 2);
+    assert_eq!(
+        body("/services/default"),
+        serde_json::json!({
+            "text": "Hello",
+            "blocks": [
+                { "type": "header", "text": { "type": "plain_text", "text": "Hello" } },
+                {
+                    "type": "section",
+                    "text": {
+                        "type": "mrkdwn",
+                        "text": "1 &lt; 2\n<https://example.com/1|Read more>",
+                    },
+                },
+                {
+                    "type": "context",
+                    "elements": [
+                        {
+                            "type": "mrkdwn",
+                            "text": "<!date^1600000000^{date_short_pretty} {time}|2020-09-13T12:26:40Z>",
+                        },
+                        {
+                            "type": "mrkdwn",
+                            "text": "<https://example.com/feed.xml|https://example.com/feed.xml>",
+                        },
+                    ],
+                },
+            ],
+        })
+    );
+    assert_eq!(body("/services/crates")["text"], "foo 1.0.0");
+}
+
+#[tokio::test]
+async fn matrix() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{run_matrix, Event, MatrixConfig, Router, TemplateConfig};
+
+    let sent = AtomicUsize::new(0);
+    let (base, requests) = mock_server(move |req| {
+        if req.starts_with("GET /_matrix/client/v3/account/whoami ") {
+            response("200 OK", &[], r#"{"user_id": "@caster:example.org"}"#)
+        } else if sent.fetch_add(1, Ordering::SeqCst) == 0 {
+            response(
+                "429 Too Many Requests",
+                &[],
+                r#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests", "retry_after_ms": 50}"#,
+            )
+        } else {
+            response("200 OK", &[], r#"{"event_id": "$event"}"#)
+        }
+    })
+    .await;
+
+    let outbox = start_consumer("consumer_matrix", Router::default(), |inbox| {
+        run_matrix(
+            inbox,
+            MatrixConfig {
+                homeserver: format!("{}/", base),
+                access_token: "secret".to_owned(),
+                rooms: vec!["!room:example.org".to_owned()],
+                msgtype: "m.notice".to_owned(),
+                content_max_length: 0,
+                retry: Default::default(),
+                templates: TemplateConfig::new(),
+            },
+        )
+    });
+
+    let id = deliver(
+        &outbox,
+        "consumer_matrix",
+        vec![Event::CratesIo {
+            name: "foo".to_owned(),
+            vers: "1.0.0".to_owned(),
+            links: None,
+            yanked: false,
+        }],
+    )
+    .await[0];
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    assert!(requests[0].contains("authorization: Bearer secret"));
+    // Retried with the same transaction ID
+    let line = format!(
+        "PUT /_matrix/client/v3/rooms/%21room%3Aexample.org/send/m.room.message/caster-{} ",
+        id
+    );
+    assert!(requests[1].starts_with(&line));
+    assert!(requests[2].starts_with(&line));
+
+    let req = &requests[2];
+    let body: serde_json::Value =
+        serde_json::from_str(&req[req.find("\r\n\r\n").unwrap() + 4..]).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "msgtype": "m.notice",
+            "body": "[ Crates.io ] New update: foo\nVersion: 1.0.0",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "[ <a href=\"https://crates.io/crates/foo\">Crates.io</a> ] New \
+                               update: <b>foo</b><br>Version: 1.0.0",
+        })
+    );
+}
+
+#[tokio::test]
+async fn email() {
+    use crate::{
+        run_email, Consumer, EmailConfig, EmailConsumer, Event, EventKind, Route, Router, SmtpTls,
+        Target, TemplateConfig,
+    };
+
+    let (port, emails) = smtp_sink().await;
+
+    // Feeds go to the team, crates to ops
+    let routes = vec![
+        Route {
+            kind: Some(EventKind::Feed),
+            consumers: vec!["consumer_email".to_owned()],
+            ..Route::default()
+        },
+        Route {
+            kind: Some(EventKind::CratesIo),
+            consumers: vec!["consumer_email".to_owned()],
+            targets: vec![Target::Name("ops@example.org".to_owned())],
+            ..Route::default()
+        },
+    ];
+    let config = EmailConfig {
+        host: "127.0.0.1".to_owned(),
+        port: Some(port),
+        tls: SmtpTls::None,
+        username: None,
+        password: None,
+        from: "Caster <caster@example.org>".to_owned(),
+        to: vec!["team@example.org".to_owned()],
+        digest_interval: Some(0.2),
+        content_max_length: 0,
+        retry: Default::default(),
+        templates: TemplateConfig::new(),
+    };
+
+    // Addresses are checked on startup
+    assert!(EmailConsumer::validate(&config, &routes).is_ok());
+    let invalid = EmailConfig {
+        from: "caster".to_owned(),
+        ..config.clone()
+    };
+    assert!(EmailConsumer::validate(&invalid, &routes).is_err());
+    let invalid = EmailConfig {
+        digest_interval: Some(f64::NAN),
+        ..config.clone()
+    };
+    assert!(EmailConsumer::validate(&invalid, &routes).is_err());
+    let route = Route {
+        consumers: vec!["consumer_email".to_owned()],
+        targets: vec![Target::Name("ops".to_owned())],
+        ..Route::default()
+    };
+    assert!(EmailConsumer::validate(&config, &[route]).is_err());
+
+    let outbox = start_consumer("consumer_email", Router::new(routes), |inbox| {
+        run_email(inbox, config)
+    });
+
+    let feed = |title: &str| Event::Feed {
+        feed: "https://example.com/feed.xml".to_owned(),
+        name: Some("Blog".to_owned()),
+        entry_id: title.to_owned(),
+        time: 1_600_000_000,
+        content: Some(format!("<p>About {}</p>", title)),
+        title: Some(title.to_owned()),
+        link: None,
+    };
+    deliver(
+        &outbox,
+        "consumer_email",
+        vec![
+            feed("First"),
+            feed("Second"),
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+
+    // One digest for each recipient
+    let mut emails = emails.lock().unwrap().clone();
+    emails.sort();
+    assert_eq!(emails.len(), 2);
+
+    let (to, data) = &emails[0];
+    assert_eq!(to, &["ops@example.org"]);
+    assert!(data.contains("Subject: [Crates.io] foo 1.0.0"));
+
+    let (to, data) = &emails[1];
+    assert_eq!(to, &["team@example.org"]);
+    assert!(data.contains("From: Caster <caster@example.org>"));
+    assert!(data.contains("Subject: 2 new events"));
+    assert!(data.contains("Content-Type: multipart/alternative"));
+    assert!(data.contains("Content-Type: text/plain"));
+    assert!(data.contains("Content-Type: text/html"));
+    assert!(data.contains("About First"));
+    assert!(data.contains("About Second"));
+}
+
+#[tokio::test]
+async fn webhook() {
+    use std::{
+        collections::BTreeMap,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use crate::{
+        run_webhook, sign, Event, EventKind, EventRecord, RetryConfig, Router, TemplateConfig,
+        WebhookConfig,
+    };
+
+    // Fails once before accepting
+    let count = AtomicUsize::new(0);
+    let (base, requests) = mock_server(move |_| {
+        if count.fetch_add(1, Ordering::SeqCst) == 0 {
+            response("502 Bad Gateway", &[], "")
+        } else {
+            response("204 No Content", &[], "")
+        }
+    })
+    .await;
+
+    let mut templates = TemplateConfig::new();
+    templates.insert(
+        EventKind::CratesIo,
+        "{{ name }}@{{ vers }} #{{ id }}".to_owned(),
+    );
+    let outbox = start_consumer("consumer_webhook", Router::default(), |inbox| {
+        run_webhook(
+            inbox,
+            WebhookConfig {
+                urls: vec![format!("{}/hook", base)],
+                headers: BTreeMap::from([("X-Token".to_owned(), "token".to_owned())]),
+                secret: Some("s3cret".to_owned()),
+                signature_header: "X-Signature".to_owned(),
+                timeout: 5.0,
+                retry: RetryConfig {
+                    initial_delay: 0.01,
+                    ..Default::default()
+                },
+                templates,
+                content_type: "text/plain".to_owned(),
+            },
+        )
+    });
+
+    let feed = Event::Feed {
+        feed: "https://example.com/feed.xml".to_owned(),
+        name: None,
+        entry_id: "1".to_owned(),
+        time: 1_600_000_000,
+        content: None,
+        title: Some("Hello".to_owned()),
+        link: None,
+    };
+    let ids = deliver(
+        &outbox,
+        "consumer_webhook",
+        vec![
+            feed.clone(),
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+    let first = ids[0];
+    let second = ids[1];
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    let parse = |req: &str| {
+        let (head, body) = req.split_once("\r\n\r\n").unwrap();
+        let headers = head
+            .lines()
+            .filter_map(|x| x.split_once(": "))
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect::<BTreeMap<_, _>>();
+        (headers, body.to_owned())
+    };
+
+    // Retried as the same delivery
+    assert_eq!(requests[0], requests[1]);
+    let (headers, body) = parse(&requests[1]);
+    assert!(requests[1].starts_with("POST /hook "));
+    assert_eq!(headers["content-type"], "application/json");
+    assert_eq!(headers["x-token"], "token");
+    assert_eq!(headers["x-caster-delivery"], first.to_string());
+    assert_eq!(
+        headers["x-signature"],
+        sign("s3cret", body.as_bytes()).unwrap()
+    );
+    assert_eq!(
+        serde_json::from_str::<EventRecord>(&body).unwrap(),
+        EventRecord(feed)
+    );
+
+    let (headers, body) = parse(&requests[2]);
+    assert_eq!(headers["content-type"], "text/plain");
+    assert_eq!(body, format!("foo@1.0.0 #{}", second));
+    assert_eq!(
+        headers["x-signature"],
+        sign("s3cret", body.as_bytes()).unwrap()
+    );
+
+    // Known value of HMAC-SHA256
+    assert_eq!(
+        sign("key", b"The quick brown fox jumps over the lazy dog").unwrap(),
+        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+}
+
+#[test]
+fn event_schema() {
+    use serde_json::json;
+
+    use crate::{Event, EventRecord};
+
+    let event = Event::CratesIo {
+        name: "foo".to_owned(),
+        vers: "1.0.0".to_owned(),
+        links: None,
+        yanked: true,
+    };
+    let record = serde_json::to_value(EventRecord(event.clone())).unwrap();
+    assert_eq!(
+        record,
+        json!({
+            "version": 1,
+            "kind": "crates_io",
+            "name": "foo",
+            "vers": "1.0.0",
+            "links": null,
+            "yanked": true,
+        })
+    );
+    assert_eq!(
+        serde_json::from_value::<EventRecord>(record.clone()).unwrap(),
+        EventRecord(event)
+    );
+
+    let mut newer = record.clone();
+    newer["version"] = 2.into();
+    assert!(serde_json::from_value::<EventRecord>(newer).is_err());
+    let mut unversioned = record;
+    unversioned.as_object_mut().unwrap().remove("version");
+    assert!(serde_json::from_value::<EventRecord>(unversioned).is_err());
+}
+
+#[tokio::test]
+async fn jsonl() {
+    use std::fs;
+
+    use crate::{run_jsonl, Event, EventRecord, JsonlConfig, Router};
+
+    let dir = std::env::temp_dir().join(format!("caster-jsonl-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("events.jsonl").to_str().unwrap().to_owned();
+
+    let events = (0..5)
+        .map(|n| Event::CratesIo {
+            name: format!("crate-{}", n),
+            vers: "1.0.0".to_owned(),
+            links: None,
+            yanked: false,
+        })
+        .collect::<Vec<_>>();
+    let line_len = serde_json::to_string(&EventRecord(events[0].clone()))
+        .unwrap()
+        .len() as u64
+        + 1;
+    // Two lines a file, keeping one rotated file
+    let config = JsonlConfig {
+        path: path.clone(),
+        max_size: Some(line_len * 2),
+        max_files: 1,
+    };
+    let outbox = start_consumer("consumer_jsonl", Router::default(), |inbox| {
+        run_jsonl(inbox, config)
+    });
+    deliver(&outbox, "consumer_jsonl", events.clone()).await;
+
+    let read = |path: &str| {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str::<EventRecord>(x).unwrap().0)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(read(&format!("{}.1", path)), events[2..4]);
+    assert_eq!(read(&path), events[4..]);
+    assert!(!dir.join("events.jsonl.2").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn feed_server() {
+    use crate::{
+        get_client, run_feed_server, Event, EventKind, FeedServerConfig, Route, Router, Target,
+    };
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let outbox = start_consumer(
+        "consumer_feed_server",
+        Router::new(vec![
+            Route {
+                kind: Some(EventKind::Feed),
+                ..Default::default()
+            },
+            Route {
+                crate_name: Some("foo".to_owned()),
+                targets: vec![Target::Name("rust".to_owned())],
+                ..Default::default()
+            },
+        ]),
+        |inbox| {
+            run_feed_server(
+                inbox,
+                FeedServerConfig {
+                    listen: format!("127.0.0.1:{}", port),
+                    base_url: None,
+                    title: "Test".to_owned(),
+                    max_entries: 2,
+                },
+            )
+        },
+    );
+
+    let feed = |n: usize| Event::Feed {
+        feed: "https://example.com/feed.xml".to_owned(),
+        name: Some("Example".to_owned()),
+        entry_id: n.to_string(),
+        time: 1_600_000_000 + n as i64,
+        content: Some("<p>Hello &amp; world</p>".to_owned()),
+        title: Some(format!("Entry {}", n)),
+        link: Some(format!("https://example.com/{}", n)),
+    };
+    // Oldest one is dropped
+    deliver(
+        &outbox,
+        "consumer_feed_server",
+        vec![
+            feed(1),
+            feed(2),
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+
+    let fetch = |path: &'static str| async move {
+        let res = get_client()
+            .get(format!("http://127.0.0.1:{}{}", port, path))
+            .send()
+            .await
+            .unwrap();
+        let content_type = res.headers()["content-type"].to_str().unwrap().to_owned();
+        let body = res.bytes().await.unwrap();
+        (content_type, feed_rs::parser::parse(&body[..]).unwrap())
+    };
+    let titles = |feed: &feed_rs::model::Feed| {
+        feed.entries
+            .iter()
+            .map(|x| x.title.as_ref().unwrap().content.clone())
+            .collect::<Vec<_>>()
+    };
+
+    for (path, content_type) in [
+        ("/feed.atom", "application/atom+xml; charset=utf-8"),
+        ("/feed.rss", "application/rss+xml; charset=utf-8"),
+        ("/feed.json", "application/feed+json; charset=utf-8"),
+    ] {
+        let (actual, feed) = fetch(path).await;
+        assert_eq!(actual, content_type);
+        assert_eq!(feed.title.as_ref().unwrap().content, "Test");
+        assert_eq!(titles(&feed), ["foo 1.0.0", "Entry 2"]);
+        assert_eq!(feed.entries[1].links[0].href, "https://example.com/2");
+    }
+
+    let (_, feed) = fetch("/rust/feed.atom").await;
+    assert_eq!(feed.title.as_ref().unwrap().content, "Test - rust");
+    assert_eq!(titles(&feed), ["foo 1.0.0"]);
+    assert_eq!(
+        feed.entries[0].links[0].href,
+        "https://crates.io/crates/foo/1.0.0"
+    );
+
+    let res = get_client()
+        .get(format!("http://127.0.0.1:{}/feed.xml", port))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+}
+
+#[tokio::test]
+async fn ntfy() {
+    use crate::{run_ntfy, DeadLetters, Event, NtfyConfig, Router, Target, TemplateConfig};
+
+    let (base, requests) = mock_server(|_| response("200 OK", &[], "{}")).await;
+
+    let outbox = start_consumer("consumer_ntfy", Router::default(), |inbox| {
+        run_ntfy(
+            inbox,
+            NtfyConfig {
+                // Second one is invalid, and kept out of dead letters
+                topics: vec![format!("{}/caster", base), "secret-topic".to_owned()],
+                token: Some("tk_token".to_owned()),
+                priority: Some(4),
+                tags: vec!["newspaper".to_owned()],
+                content_max_length: 0,
+                retry: Default::default(),
+                templates: TemplateConfig::new(),
+            },
+        )
+    });
+
+    deliver(
+        &outbox,
+        "consumer_ntfy",
+        vec![
+            Event::Feed {
+                feed: "https://example.com/feed.xml".to_owned(),
+                name: Some("Example".to_owned()),
+                entry_id: "1".to_owned(),
+                time: 1_600_000_000,
+                content: Some("<p>1 &lt; 2</p>".to_owned()),
+                title: Some("Héllo".to_owned()),
+                link: Some("https://example.com/1".to_owned()),
+            },
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: true,
+            },
+        ],
+    )
+    .await;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    let body = |req: &str| -> serde_json::Value {
+        serde_json::from_str(&req[req.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    };
+    // Published as JSON to root of server
+    assert!(requests[0].starts_with("POST / "));
+    assert!(requests[0].contains("authorization: Bearer tk_token\r\n"));
+    assert_eq!(
+        body(&requests[0]),
+        serde_json::json!({
+            "topic": "caster",
+            "title": "Héllo",
+            "message": "1 < 2",
+            "priority": 4,
+            "tags": ["newspaper"],
+            "click": "https://example.com/1",
+        })
+    );
+    assert_eq!(
+        body(&requests[1]),
+        serde_json::json!({
+            "topic": "caster",
+            "title": "foo 1.0.0",
+            "message": "New update: foo 1.0.0\nYanked: true",
+            "priority": 4,
+            "tags": ["newspaper"],
+            "click": "https://crates.io/crates/foo/1.0.0",
+        })
+    );
+    drop(requests);
+
+    let letters = DeadLetters::open(test_db())
+        .unwrap()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.consumer == "consumer_ntfy")
+        .collect::<Vec<_>>();
+    assert_eq!(letters.len(), 2);
+    for letter in letters {
+        // Topics are referred to by index
+        assert_eq!(letter.targets, [Target::Id(1)]);
+        assert!(!letter.error.contains("secret-topic"));
+    }
+}
+
+#[tokio::test]
+async fn gotify() {
+    use crate::{
+        run_gotify, DeadLetters, Event, EventKind, GotifyConfig, Route, Router, Target,
+        TemplateConfig,
+    };
+
+    let (base, requests) = mock_server(|req| {
+        if req.contains("x-gotify-key: invalid\r\n") {
+            response("401 Unauthorized", &[], r#"{"error":"Unauthorized"}"#)
+        } else {
+            response("200 OK", &[], "{}")
+        }
+    })
+    .await;
+
+    // Crates go to an application of invalid token as well
+    let router = Router::new(vec![
+        Route {
+            crate_name: Some("foo".to_owned()),
+            targets: vec![Target::Id(0), Target::Id(1)],
+            ..Route::default()
+        },
+        Route {
+            kind: Some(EventKind::Feed),
+            targets: vec![Target::Id(0)],
+            ..Route::default()
+        },
+    ]);
+    // Fails to render feeds without content
+    let templates = [(EventKind::Feed, "{{ content | upper }}".to_owned())]
+        .into_iter()
+        .collect::<TemplateConfig>();
+    let outbox = start_consumer("consumer_gotify", router, |inbox| {
+        run_gotify(
+            inbox,
+            GotifyConfig {
+                server: format!("{}/", base),
+                tokens: vec!["app".to_owned(), "invalid".to_owned()],
+                priority: Some(8),
+                markdown: true,
+                content_max_length: 0,
+                retry: Default::default(),
+                templates,
+            },
+        )
+    });
+
+    deliver(
+        &outbox,
+        "consumer_gotify",
+        vec![
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+            Event::Feed {
+                feed: "https://example.com/feed.xml".to_owned(),
+                name: None,
+                entry_id: "1".to_owned(),
+                time: 1_600_000_000,
+                content: None,
+                title: Some("Hello".to_owned()),
+                link: None,
+            },
+        ],
+    )
+    .await;
+
+    let requests = requests.lock().unwrap();
+    // Unauthorized is not retried
+    assert_eq!(requests.len(), 2);
+    let req = requests
+        .iter()
+        .find(|x| x.contains("x-gotify-key: app\r\n"))
+        .unwrap();
+    assert!(req.starts_with("POST /message "));
+    let body: serde_json::Value =
+        serde_json::from_str(&req[req.find("\r\n\r\n").unwrap() + 4..]).unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "title": "foo 1.0.0",
+            "message": "New update: **foo** 1.0.0",
+            "priority": 8,
+            "extras": {
+                "client::display": { "contentType": "text/markdown" },
+                "client::notification": {
+                    "click": { "url": "https://crates.io/crates/foo/1.0.0" },
+                },
+            },
+        })
+    );
+
+    let letters = DeadLetters::open(test_db())
+        .unwrap()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.consumer == "consumer_gotify")
+        .collect::<Vec<_>>();
+    assert_eq!(letters.len(), 2);
+    // Tokens are referred to by index
+    assert_eq!(letters[0].targets, [Target::Id(1)]);
+    assert!(!letters[0].error.contains("invalid"));
+    // Events failing to render are not retried on restart
+    assert_eq!(letters[1].targets, [Target::Id(0)]);
+    assert!(letters[1]
+        .error
+        .contains("Failed to render template of feed"));
+}
+
+#[tokio::test]
+async fn irc() {
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        one_line, run_irc, DeadLetters, Event, EventKind, IrcConfig, RetryConfig, Route, Router,
+        SaslConfig, Target, TemplateConfig,
+    };
+
+    // Lines received on each connection, with time of receiving
+    let lines = Arc::new(Mutex::new(Vec::<Vec<(Instant, String)>>::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let recorded = lines.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let n = {
+                let mut lines = recorded.lock().unwrap();
+                lines.push(vec![]);
+                lines.len() - 1
+            };
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader).lines();
+            let mut nick_taken = n == 1;
+            while let Ok(Some(line)) = reader.next_line().await {
+                recorded.lock().unwrap()[n].push((Instant::now(), line.clone()));
+                let reply = match line.split(' ').next().unwrap() {
+                    // Registration completes once capability negotiation ends,
+                    // and first connection drops right after it
+                    "CAP" if line == "CAP END" && n == 0 => {
+                        writer
+                            .write_all(b":irc.test 001 caster :Welcome\r\n")
+                            .await
+                            .unwrap();
+                        break;
+                    }
+                    "CAP" if line == "CAP END" => {
+                        ":irc.test 001 caster_ :Welcome\r\nPING :irc.test".to_owned()
+                    }
+                    "CAP" => ":irc.test CAP * ACK :sasl".to_owned(),
+                    "AUTHENTICATE" if line == "AUTHENTICATE PLAIN" => "AUTHENTICATE +".to_owned(),
+                    "AUTHENTICATE" => {
+                        ":irc.test 903 caster :SASL authentication successful".to_owned()
+                    }
+                    "NICK" if nick_taken => {
+                        nick_taken = false;
+                        ":irc.test 433 * caster :Nickname is already in use".to_owned()
+                    }
+                    "JOIN" if line == "JOIN #closed" => {
+                        ":irc.test 474 caster_ #closed :Cannot join channel (+b)".to_owned()
+                    }
+                    "JOIN" => format!(":caster_!caster@irc.test {}", line),
+                    _ => continue,
+                };
+                writer
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let router = Router::new(vec![
+        Route {
+            kind: Some(EventKind::Feed),
+            ..Route::default()
+        },
+        Route {
+            crate_name: Some("foo".to_owned()),
+            targets: vec![
+                Target::Name("#rust".to_owned()),
+                Target::Name("#closed".to_owned()),
+            ],
+            ..Route::default()
+        },
+    ]);
+    let outbox = start_consumer("consumer_irc", router, |inbox| {
+        run_irc(
+            inbox,
+            IrcConfig {
+                server: "127.0.0.1".to_owned(),
+                port: Some(port),
+                tls: false,
+                nickname: "caster".to_owned(),
+                username: None,
+                realname: Some("Caster bot".to_owned()),
+                password: None,
+                sasl: Some(SaslConfig {
+                    username: "caster".to_owned(),
+                    password: "pass".to_owned(),
+                }),
+                nickserv_password: Some("secret".to_owned()),
+                channels: vec!["#caster".to_owned()],
+                throttle_interval: 0.1,
+                throttle_burst: 1,
+                reconnect_delay: 0.05,
+                retry: RetryConfig {
+                    max_retries: 1,
+                    initial_delay: 0.01,
+                    ..Default::default()
+                },
+                templates: TemplateConfig::new(),
+            },
+        )
+    });
+
+    deliver(
+        &outbox,
+        "consumer_irc",
+        vec![
+            Event::Feed {
+                feed: "https://example.com/feed.xml".to_owned(),
+                name: Some("Example".to_owned()),
+                entry_id: "1".to_owned(),
+                time: 1_600_000_000,
+                content: Some("<p>Hello</p>".to_owned()),
+                title: Some("Hello\nworld".to_owned()),
+                link: Some("https://example.com/1".to_owned()),
+            },
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 2);
+    let second = lines[1].iter().map(|x| x.1.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        second,
+        [
+            "CAP REQ :sasl",
+            "NICK caster",
+            "USER caster 0 * :Caster bot",
+            "AUTHENTICATE PLAIN",
+            "NICK caster_",
+            // `caster\0caster\0pass`
+            "AUTHENTICATE Y2FzdGVyAGNhc3RlcgBwYXNz",
+            "CAP END",
+            // Not identifying to NickServ once logged in with SASL
+            "JOIN #caster",
+            "PONG :irc.test",
+            "PRIVMSG #caster :[Example] Hello world https://example.com/1",
+            "JOIN #rust",
+            "PRIVMSG #rust :foo 1.0.0 https://crates.io/crates/foo/1.0.0",
+            // Banned, retried once
+            "JOIN #closed",
+            "JOIN #closed",
+        ]
+    );
+    // Throttled
+    let sent = lines[1]
+        .iter()
+        .filter(|x| x.1.starts_with("PRIVMSG #"))
+        .map(|x| x.0)
+        .collect::<Vec<_>>();
+    assert!(sent[1] - sent[0] >= Duration::from_millis(90));
+
+    let letters = DeadLetters::open(test_db())
+        .unwrap()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.consumer == "consumer_irc")
+        .collect::<Vec<_>>();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].targets, [Target::Name("#closed".to_owned())]);
+
+    let long = "a ".repeat(300) + "é".repeat(300).as_str();
+    let cut = one_line(&long);
+    assert!(cut.len() <= 400);
+    assert!(cut.ends_with('…'));
+}
+
+#[tokio::test]
+async fn mastodon() {
+    use crate::{
+        fit_status, run_mastodon, status_length, DeadLetters, Event, MastodonConfig, Router,
+        TemplateConfig, Visibility,
+    };
+
+    let (base, requests) = mock_server(|req| {
+        if req.contains("crates.io") {
+            response("422 Unprocessable Entity", &[], r#"{"error":"Invalid"}"#)
+        } else {
+            response("200 OK", &[], r#"{"id":"1"}"#)
+        }
+    })
+    .await;
+
+    let outbox = start_consumer("consumer_mastodon", Router::default(), |inbox| {
+        run_mastodon(
+            inbox,
+            MastodonConfig {
+                instance: format!("{}/", base),
+                access_token: "token".to_owned(),
+                visibility: Visibility::Unlisted,
+                content_warning: Some("CW".to_owned()),
+                language: None,
+                max_characters: 100,
+                content_max_length: 0,
+                retry: Default::default(),
+                templates: TemplateConfig::new(),
+            },
+        )
+    });
+
+    let link = format!("https://example.com/{}", "a".repeat(50));
+    let feed = deliver(
+        &outbox,
+        "consumer_mastodon",
+        vec![
+            Event::Feed {
+                feed: "https://example.com/feed.xml".to_owned(),
+                name: None,
+                entry_id: "1".to_owned(),
+                time: 1_600_000_000,
+                content: Some(format!("<p>{}</p>", "word ".repeat(50))),
+                title: Some("Hello".to_owned()),
+                link: Some(link.clone()),
+            },
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await[0];
+
+    let requests = requests.lock().unwrap();
+    // Invalid status is not retried
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].starts_with("POST /api/v1/statuses "));
+    assert!(requests[0].contains("authorization: Bearer token\r\n"));
+    assert!(requests[0].contains(&format!("idempotency-key: caster-{}\r\n", feed)));
+    let body: serde_json::Value =
+        serde_json::from_str(&requests[0][requests[0].find("\r\n\r\n").unwrap() + 4..]).unwrap();
+    assert_eq!(body["visibility"], "unlisted");
+    assert_eq!(body["spoiler_text"], "CW");
+    let status = body["status"].as_str().unwrap();
+    assert!(status.starts_with("Hello\n\nword word"));
+    assert!(status.ends_with(&format!("…\n{}", link)));
+    assert_eq!(status_length(status), 98);
+
+    let letters = DeadLetters::open(test_db())
+        .unwrap()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.consumer == "consumer_mastodon")
+        .count();
+    assert_eq!(letters, 1);
+
+    // Urls count as 23 characters
+    let status = format!("New {}", link);
+    assert_eq!(status_length(&status), 27);
+    assert_eq!(fit_status(&status, 27), status);
+    assert_eq!(fit_status("a b c d e f", 5), "a b …");
+}
+
+#[tokio::test]
+async fn mqtt() {
+    use crate::{
+        run_mqtt, Consumer, DeadLetters, Event, EventRecord, MqttConfig, MqttConsumer, Route,
+        Router, Target,
+    };
+
+    // Packets received on each connection, as first byte and the rest
+    let packets = Arc::new(Mutex::new(Vec::<Vec<(u8, Vec<u8>)>>::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let recorded = packets.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let n = {
+                let mut packets = recorded.lock().unwrap();
+                packets.push(vec![]);
+                packets.len() - 1
+            };
+            while let Ok(header) = stream.read_u8().await {
+                let mut len = 0;
+                for shift in (0..28).step_by(7) {
+                    let byte = stream.read_u8().await.unwrap();
+                    len |= ((byte & 0x7f) as usize) << shift;
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                }
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).await.unwrap();
+                recorded.lock().unwrap()[n].push((header, body.clone()));
+
+                let reply = match header >> 4 {
+                    // First connection is refused as not authorized
+                    1 => vec![0x20, 2, 0, if n == 0 { 5 } else { 0 }],
+                    // Publish with QoS 2, to be released
+                    3 => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let id = &body[2 + topic_len..4 + topic_len];
+                        vec![0x50, 2, id[0], id[1]]
+                    }
+                    6 => vec![0x70, 2, body[0], body[1]],
+                    _ => continue,
+                };
+                stream.write_all(&reply).await.unwrap();
+            }
+        }
+    });
+
+    let router = Router::new(vec![
+        Route {
+            feed: Some("https://example.com/feed.xml".to_owned()),
+            ..Route::default()
+        },
+        Route {
+            crate_name: Some("foo".to_owned()),
+            targets: vec![
+                Target::Name("home/crates".to_owned()),
+                Target::Name("home/+".to_owned()),
+            ],
+            ..Route::default()
+        },
+    ]);
+    let config = MqttConfig {
+        host: "127.0.0.1".to_owned(),
+        port: Some(port),
+        tls: false,
+        username: Some("user".to_owned()),
+        password: Some("pass".to_owned()),
+        client_id: "caster".to_owned(),
+        topic_prefix: "caster".to_owned(),
+        qos: 2,
+        retain: true,
+        keep_alive: 60,
+        reconnect_delay: 0.05,
+    };
+
+    // Password without username, and wildcards in topics of routes are
+    // rejected on startup
+    assert!(MqttConsumer::validate(&config, &[]).is_ok());
+    let invalid = MqttConfig {
+        username: None,
+        ..config.clone()
+    };
+    assert!(MqttConsumer::validate(&invalid, &[]).is_err());
+    let route = Route {
+        consumers: vec!["consumer_mqtt".to_owned()],
+        targets: vec![Target::Name("home/#".to_owned())],
+        ..Route::default()
+    };
+    assert!(MqttConsumer::validate(&config, &[route]).is_err());
+    let invalid = MqttConfig {
+        reconnect_delay: -1.0,
+        ..config.clone()
+    };
+    assert!(MqttConsumer::validate(&invalid, &[]).is_err());
+    let invalid = MqttConfig {
+        topic_prefix: "".to_owned(),
+        ..config.clone()
+    };
+    assert!(MqttConsumer::validate(&invalid, &[]).is_err());
+    let invalid = MqttConfig {
+        client_id: "x".repeat(u16::MAX as usize + 1),
+        ..config.clone()
+    };
+    assert!(MqttConsumer::validate(&invalid, &[]).is_err());
+
+    let outbox = start_consumer("consumer_mqtt", router, |inbox| run_mqtt(inbox, config));
+
+    let feed = Event::Feed {
+        feed: "https://example.com/feed.xml".to_owned(),
+        name: Some("Example/blog".to_owned()),
+        entry_id: "1".to_owned(),
+        time: 1_600_000_000,
+        content: None,
+        title: Some("Hello".to_owned()),
+        link: Some("https://example.com/1".to_owned()),
+    };
+    deliver(
+        &outbox,
+        "consumer_mqtt",
+        vec![
+            feed.clone(),
+            Event::CratesIo {
+                name: "foo".to_owned(),
+                vers: "1.0.0".to_owned(),
+                links: None,
+                yanked: false,
+            },
+        ],
+    )
+    .await;
+
+    let packets = packets.lock().unwrap();
+    assert_eq!(packets.len(), 2);
+    let (header, connect) = &packets[1][0];
+    assert_eq!(*header, 0x10);
+    // Protocol, level 4, with username, password and clean session
+    assert_eq!(&connect[..8], b"\x00\x04MQTT\x04\xc2");
+    assert!(connect.ends_with(b"\x00\x06caster\x00\x04user\x00\x04pass"));
+
+    let published = packets[1]
+        .iter()
+        .filter(|x| x.0 >> 4 == 3)
+        .map(|(header, body)| {
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            (*header, topic, body[4 + topic_len..].to_vec())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(published.len(), 2);
+    // QoS 2 and retained
+    assert_eq!(published[0].0, 0x35);
+    assert_eq!(published[0].1, "caster/feed/Example_blog");
+    assert_eq!(
+        serde_json::from_slice::<EventRecord>(&published[0].2).unwrap(),
+        EventRecord(feed)
+    );
+    assert_eq!(published[1].1, "home/crates");
+    // Released after each publish
+    assert_eq!(packets[1].iter().filter(|x| x.0 == 0x62).count(), 2);
+
+    let letters = DeadLetters::open(test_db())
+        .unwrap()
+        .list()
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.consumer == "consumer_mqtt")
+        .collect::<Vec<_>>();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].targets, [Target::Name("home/+".to_owned())]);
+}
+
+#[tokio::test]
+async fn github() {
+    use std::time::Duration;
+
+    use crate::{Event, GitHubCaster, GitHubConfig, Outbox, Route, Router, Target, Targets};
+
+    let release = |tag: &str, prerelease: bool, draft: bool| {
+        serde_json::json!({
+            "tag_name": tag,
+            "name": format!("Release {}", tag),
+            "body": "- Fixed things",
+            "html_url": format!("https://github.com/foo/bar/releases/tag/{}", tag),
+            "draft": draft,
+            "prerelease": prerelease,
+            "published_at": "2022-01-01T00:00:00Z",
+        })
+    };
+    let first = serde_json::json!([
+        release("v1.0.0", false, false),
+        release("v0.9.0", false, false)
+    ]);
+    let second = serde_json::json!([
+        release("v2.0.0", false, true),
+        release("v1.2.0-rc.1", true, false),
+        release("v1.1.0", false, false),
+        release("v1.0.0", false, false),
+        release("v0.9.0", false, false),
+    ]);
+    // Release of a tag pushed earlier
+    let third = serde_json::json!([
+        release("v3.0.0", false, false),
+        release("v1.1.0", false, false),
+    ]);
+    let (addr, requests) = mock_server(move |req| {
+        let (body, etag) = match (req.contains("/releases "), req) {
+            (true, x) if x.contains("if-none-match: \"r3\"") => {
+                return response("304 Not Modified", &[], "")
+            }
+            (true, x) if x.contains("if-none-match: \"r2\"") => (third.to_string(), "\"r3\""),
+            (true, x) if x.contains("if-none-match: \"r1\"") => (second.to_string(), "\"r2\""),
+            (true, _) => (first.to_string(), "\"r1\""),
+            (false, x) if x.contains("if-none-match: \"t2\"") => {
+                return response("304 Not Modified", &[], "")
+            }
+            (false, x) if x.contains("if-none-match: \"t1\"") => (
+                r#"[{"name":"v3.0.0"},{"name":"v1.1.0"},{"name":"nightly"},{"name":"v1.0.0"}]"#
+                    .to_owned(),
+                "\"t2\"",
+            ),
+            (false, _) => (
+                r#"[{"name":"v1.0.0"},{"name":"v0.1.0"}]"#.to_owned(),
+                "\"t1\"",
+            ),
+        };
+        response("200 OK", &[("ETag", etag)], &body)
+    })
+    .await;
+
+    let outbox = Outbox::open(
+        test_db().open_tree("outbox-github").unwrap(),
+        vec!["consumer_test"],
+        16,
+    )
+    .unwrap();
+    let config = GitHubConfig {
+        repos: vec!["foo/bar".to_owned()],
+        token: Some("secret".to_owned()),
+        api_url: addr.clone(),
+        prereleases: false,
+        tags: true,
+        interval: 0.1,
+    };
+    let handle = <GitHubCaster as crate::Caster>::run(outbox.clone(), config);
+    tokio::time::sleep(Duration::from_millis(450)).await;
+    handle.abort();
+
+    let requests = requests.lock().unwrap();
+    assert!(requests.len() >= 6);
+    assert!(requests[0].starts_with("GET /repos/foo/bar/releases "));
+    assert!(requests[0].contains("authorization: Bearer secret"));
+    assert!(requests[0].contains("user-agent: caster"));
+    assert!(requests[0].contains("accept: application/vnd.github+json"));
+    assert!(requests[1].starts_with("GET /repos/foo/bar/tags "));
+    assert!(requests[2].contains("if-none-match: \"r1\""));
+
+    // Only latest release when first seen, then new releases and tags,
+    // without drafts and pre-releases. Tags of releases sent are left out,
+    // while releases of tags sent are not.
+    let events = outbox
+        .store()
+        .pending("consumer_test")
+        .unwrap()
+        .into_iter()
+        .map(|x| x.event)
+        .collect::<Vec<_>>();
+    let tags = events
+        .iter()
+        .map(|x| match x {
+            Event::GitHubRelease { tag, .. } => tag.as_str(),
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tags, ["v1.0.0", "v1.1.0", "nightly", "v3.0.0", "v3.0.0"]);
+    assert_eq!(
+        events[1],
+        Event::GitHubRelease {
+            repo: "foo/bar".to_owned(),
+            tag: "v1.1.0".to_owned(),
+            name: Some("Release v1.1.0".to_owned()),
+            body: Some("- Fixed things".to_owned()),
+            link: "https://github.com/foo/bar/releases/tag/v1.1.0".to_owned(),
+            prerelease: false,
+            time: 1_640_995_200,
+        }
+    );
+    assert_eq!(
+        events[2].link().unwrap(),
+        format!("{}/foo/bar/releases/tag/nightly", addr)
+    );
+    assert!(matches!(
+        &events[3],
+        Event::GitHubRelease { name: None, .. }
+    ));
+    assert!(
+        matches!(&events[4], Event::GitHubRelease { name: Some(x), .. } if x == "Release v3.0.0")
+    );
+
+    let router = Router::new(vec![Route {
+        repo: Some("foo/bar".to_owned()),
+        title: Some(regex::Regex::new("^Release").unwrap()),
+        targets: vec![Target::Id(-100)],
+        ..Route::default()
+    }]);
+    assert_eq!(
+        router.route(&events[1], "consumer_test"),
+        Some(Targets::Only(vec![Target::Id(-100)]))
+    );
+    assert_eq!(router.route(&events[2], "consumer_test"), None);
+}
+
+#[tokio::test]
+async fn git() {
+    use std::time::Duration;
+
+    use git2::{Oid, Repository, RepositoryInitOptions, Signature, Time};
+
+    use crate::{Event, GitCaster, GitConfig, GitRepo, Outbox};
+
+    let dir = std::env::temp_dir().join(format!("caster-git-test-{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let repo = Repository::init_opts(
+        dir.join("source"),
+        RepositoryInitOptions::new().initial_head("main"),
+    )
+    .unwrap();
+    let sig = Signature::new("Alice", "alice@example.com", &Time::new(1_600_000_000, 0)).unwrap();
+    let tree = repo
+        .find_tree(repo.index().unwrap().write_tree().unwrap())
+        .unwrap();
+    let commit = |branch: &str, parent: Option<Oid>, message: &str| {
+        let parents = parent
+            .map(|x| repo.find_commit(x).unwrap())
+            .into_iter()
+            .collect::<Vec<_>>();
+        repo.commit(
+            Some(&format!("refs/heads/{}", branch)),
+            &sig,
+            &sig,
+            message,
+            &tree,
+            &parents.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    };
+
+    let initial = commit("main", None, "Initial commit");
+    commit("dev", Some(initial), "Work in progress");
+    let object = repo.find_object(initial, None).unwrap();
+    repo.tag_lightweight("v0.1.0", &object, false).unwrap();
+
+    let outbox = Outbox::open(
+        test_db().open_tree("outbox-git").unwrap(),
+        vec!["consumer_test"],
+        16,
+    )
+    .unwrap();
+    let config = GitConfig {
+        repos: vec![GitRepo {
+            url: format!("file://{}", dir.join("source").display()),
+            name: Some("source".to_owned()),
+            branches: vec!["main".to_owned()],
+            tags: true,
+        }],
+        cache_dir: dir.join("cache").to_str().unwrap().to_owned(),
+        interval: 0.1,
+        max_commits: 20,
+    };
+    let handle = <GitCaster as crate::Caster>::run(outbox.clone(), config);
+    let pending = || outbox.store().pending("consumer_test").unwrap();
+
+    // Existing commits and tags are only recorded
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(pending().is_empty());
+
+    let first = commit("main", Some(initial), "Add foo\n\nDetails of foo\n");
+    let second = commit("main", Some(first), "Fix bar");
+    let dev = repo.refname_to_id("refs/heads/dev").unwrap();
+    commit("dev", Some(dev), "More work");
+    let object = repo.find_object(second, None).unwrap();
+    repo.tag("v0.2.0", &object, &sig, "Version 0.2.0", false)
+        .unwrap();
+    // Tags of trees are skipped without holding back others
+    let tree = repo.find_commit(second).unwrap().tree_id();
+    let object = repo.find_object(tree, None).unwrap();
+    repo.tag_lightweight("a-tree", &object, false).unwrap();
+
+    for _ in 0..50 {
+        if pending().len() >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    handle.abort();
+
+    // Commits of watched branch in order, then the new tag
+    let events = pending().into_iter().map(|x| x.event).collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        [
+            Event::Git {
+                repo: "source".to_owned(),
+                branch: Some("main".to_owned()),
+                tag: None,
+                commit: first.to_string(),
+                author: "Alice".to_owned(),
+                message: "Add foo\n\nDetails of foo".to_owned(),
+                time: 1_600_000_000,
+            },
+            Event::Git {
+                repo: "source".to_owned(),
+                branch: Some("main".to_owned()),
+                tag: None,
+                commit: second.to_string(),
+                author: "Alice".to_owned(),
+                message: "Fix bar".to_owned(),
+                time: 1_600_000_000,
+            },
+            Event::Git {
+                repo: "source".to_owned(),
+                branch: None,
+                tag: Some("v0.2.0".to_owned()),
+                commit: second.to_string(),
+                author: "Alice".to_owned(),
+                message: "Version 0.2.0".to_owned(),
+                time: 1_600_000_000,
+            },
+        ]
+    );
+    std::fs::remove_dir_all(&dir).ok();
+}
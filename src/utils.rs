@@ -5,11 +5,20 @@ use std::{
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use color_eyre::{config::HookBuilder, eyre::Context, Result};
+use color_eyre::{
+    config::HookBuilder,
+    eyre::{eyre, Context},
+    Report, Result,
+};
 use humantime::Rfc3339Timestamp;
 use once_cell::sync::Lazy;
 use pretty_env_logger::formatted_timed_builder;
 use reqwest::Client;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
 
 use crate::Config;
 
@@ -49,6 +58,50 @@ pub fn get_client<'a>() -> &'a Client {
     &CLIENT
 }
 
+/// `e` without url of the request, for urls that are secret (e.g. webhooks)
+pub fn hide_url(e: reqwest::Error) -> Report {
+    let msg = e.to_string();
+    match e.url() {
+        Some(url) => eyre!("{}", msg.replace(&format!(" for url ({})", url), "")),
+        None => eyre!("{}", msg),
+    }
+}
+
+/// Check that `secs` of option `name` can be turned into a [`Duration`],
+/// which panics on negative, infinite or NaN seconds otherwise
+pub fn check_secs(name: &str, secs: f64) -> Result<()> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(eyre!("Invalid `{}` {}, should be seconds", name, secs)),
+    }
+}
+
+/// A plain or TLS connection
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Connect to `host` over TCP, with TLS if `tls` is set
+pub async fn connect_stream(host: &str, port: u16, tls: bool) -> Result<Box<dyn Stream>> {
+    let tcp = TcpStream::connect((host, port))
+        .await
+        .wrap_err_with(|| format!("Failed to connect to {}:{}", host, port))?;
+    // Messages of line or packet based protocols are small and should go out
+    // right away
+    tcp.set_nodelay(true).ok();
+    if !tls {
+        return Ok(Box::new(tcp));
+    }
+
+    let connector =
+        TlsConnector::from(native_tls::TlsConnector::new().wrap_err("Failed to set up TLS")?);
+    let stream = connector
+        .connect(host, tcp)
+        .await
+        .wrap_err("Failed to establish TLS")?;
+    Ok(Box::new(stream))
+}
+
 pub struct Interval {
     interval: Duration,
     deadline: Option<Instant>,
@@ -86,3 +139,128 @@ impl Interval {
         sleep(self.next_tick())
     }
 }
+
+/// Cut `text` to at most `max_len` characters, ending with `…` if cut
+pub fn truncate_chars(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        text.to_owned()
+    } else {
+        text.chars()
+            .take(max_len.saturating_sub(1))
+            .chain(['…'])
+            .collect()
+    }
+}
+
+/// Split `html` into parts of at most `max_len` characters, between paragraphs
+/// if possible, otherwise between lines, words or characters. Tags open at a
+/// split are closed at the end of the part and opened again in the next one.
+/// Tags too long to fit in a part are split like text.
+pub fn split_html(html: &str, max_len: usize) -> Vec<String> {
+    let max_len = max_len.max(1);
+    let mut parts = vec![];
+    let mut rest = html.trim().to_owned();
+
+    while rest.chars().count() > max_len {
+        let (cut, open) = find_cut(&rest, max_len);
+        let closing = open
+            .iter()
+            .rev()
+            .map(|(name, _)| format!("</{}>", name))
+            .collect::<String>();
+        let opening = open.iter().map(|(_, tag)| *tag).collect::<String>();
+        let (part, tail) = rest.split_at(cut);
+
+        parts.push(format!("{}{}", part.trim_end(), closing));
+        rest = opening + tail.trim_start();
+    }
+    parts.push(rest);
+    parts
+}
+
+/// Tags open at some point of HTML, as (name, tag)
+type OpenTags<'a> = Vec<(&'a str, &'a str)>;
+
+/// Best place to split `html` so that the first part, with open tags closed,
+/// fits in `max_len`. Returns byte index and tags open there.
+fn find_cut(html: &str, max_len: usize) -> (usize, OpenTags<'_>) {
+    // Candidates by preference: paragraph, line, word, character
+    let mut cuts: [Option<(usize, OpenTags)>; 4] = Default::default();
+    let mut open: OpenTags = vec![];
+    let mut closing_len = 0;
+    let mut len = 0;
+    let mut has_text = false;
+    let mut i = 0;
+
+    while let Some(c) = html[i..].chars().next() {
+        if len + closing_len > max_len {
+            break;
+        }
+        if has_text {
+            let rank = match c {
+                '\n' if html[i..].starts_with("\n\n") => 0,
+                '\n' => 1,
+                ' ' => 2,
+                _ => 3,
+            };
+            cuts[rank] = Some((i, open.clone()));
+        }
+
+        // Tags and entities can't be split
+        let end = match c {
+            '<' => html[i..].find('>'),
+            '&' => html[i..].find(';').filter(|x| *x < 10),
+            _ => None,
+        };
+        let Some(end) = end.map(|x| i + x + 1) else {
+            len += 1;
+            has_text = true;
+            i += c.len_utf8();
+            continue;
+        };
+
+        let token = &html[i..end];
+        len += token.chars().count();
+        if let Some(name) = token.strip_prefix("</") {
+            let name = name.trim_end_matches('>').trim();
+            if let Some(pos) = open.iter().rposition(|(x, _)| *x == name) {
+                open.remove(pos);
+                closing_len -= name.len() + 3;
+            }
+        } else if c == '<' && !token.ends_with("/>") {
+            let name = token[1..]
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default();
+            open.push((name, token));
+            closing_len += name.len() + 3;
+        } else if c == '&' {
+            has_text = true;
+        }
+        i = end;
+    }
+
+    cuts.into_iter().flatten().next().unwrap_or_else(|| {
+        // Nothing fits before a tag or entity longer than `max_len`, which
+        // can't be kept whole
+        let cut = html.char_indices().nth(max_len).map_or(html.len(), |x| x.0);
+        (cut, vec![])
+    })
+}
+
+/// (De)serialize `Option<Regex>` as an optional string, for use with
+/// `#[serde(default, with = "serde_regex")]`
+pub mod serde_regex {
+    use regex::Regex;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Option<Regex>, ser: S) -> Result<S::Ok, S::Error> {
+        regex.as_ref().map(Regex::as_str).serialize(ser)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<Regex>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|x| Regex::new(&x).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}